@@ -4,6 +4,15 @@ use std::any::Any;
 pub enum Action {
   DeleteQueue(String),
   RetryAll,
+  /// A job was pushed onto a queue, holds the queue name and the job's JSON payload
+  EnqueueJob(String, String),
+  /// A queue was paused, with the number of seconds until it resumes if it expires
+  PauseQueue(String, Option<usize>),
+  ResumeQueue(String),
+  /// Failed jobs matching a filter were retried, holds how many were requeued
+  RetryMatching(u64),
+  /// Failed jobs matching a filter were deleted, holds how many were removed
+  DeleteMatching(u64),
 }
 
 /// Defines an interface for plugins to adhere to.
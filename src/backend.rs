@@ -0,0 +1,262 @@
+use crate::handlers::AppState;
+use crate::resque;
+use actix_web::{dev, error, web, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use plugin_manager::Action;
+use redis::aio::ConnectionManager;
+use serde_derive::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Requests pick the Redis backend they run against with this header. Requests without
+/// it use the first configured backend.
+pub const BACKEND_HEADER: &str = "X-Resque-Backend";
+
+/// A named Redis connection and the Resque namespace used inside of it.
+#[derive(Clone)]
+pub struct Backend {
+    pub name: String,
+    pub redis: ConnectionManager,
+    pub namespace: resque::Namespace,
+}
+
+/// A backend as configured. Its connection is opened the first time it's needed and
+/// retried on later uses if that fails, so one unreachable Redis doesn't stop the server.
+#[derive(Clone)]
+pub struct ConfiguredBackend {
+    pub name: String,
+    namespace: resque::Namespace,
+    client: redis::Client,
+    redis: Arc<Mutex<Option<ConnectionManager>>>,
+}
+
+#[derive(Serialize)]
+pub struct BackendHealth {
+    name: String,
+    namespace: String,
+    healthy: bool,
+    error: Option<String>,
+}
+
+impl ConfiguredBackend {
+    pub fn new(name: String, client: redis::Client, namespace: resque::Namespace) -> Self {
+        ConfiguredBackend {
+            name,
+            namespace,
+            client,
+            redis: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The backend's connection, opening it if this is the first use or the last attempt
+    /// failed.
+    pub async fn connect(&self) -> redis::RedisResult<Backend> {
+        let mut redis = self.redis.lock().await;
+        let con = match redis.as_ref() {
+            Some(con) => con.clone(),
+            None => {
+                let opening = self.client.get_tokio_connection_manager();
+                let con = match actix_rt::time::timeout(Duration::from_secs(5), opening).await {
+                    Ok(con) => con?,
+                    Err(_) => {
+                        return Err(redis::RedisError::from((
+                            redis::ErrorKind::IoError,
+                            "timed out connecting",
+                        )))
+                    }
+                };
+                redis.insert(con).clone()
+            }
+        };
+        Ok(Backend {
+            name: self.name.clone(),
+            redis: con,
+            namespace: self.namespace.clone(),
+        })
+    }
+
+    pub async fn health(&self) -> BackendHealth {
+        match self.connect().await {
+            Ok(backend) => backend.health().await,
+            Err(err) => BackendHealth {
+                name: self.name.clone(),
+                namespace: self.namespace.prefix().to_string(),
+                healthy: false,
+                error: Some(err.to_string()),
+            },
+        }
+    }
+}
+
+impl Backend {
+    pub async fn health(&self) -> BackendHealth {
+        let mut con = self.redis.clone();
+        let cmd = redis::cmd("PING");
+        let ping = cmd.query_async::<_, String>(&mut con);
+        let error = match actix_rt::time::timeout(Duration::from_secs(2), ping).await {
+            Ok(Ok(_)) => None,
+            Ok(Err(err)) => Some(err.to_string()),
+            Err(_) => Some(String::from("timed out waiting for PING")),
+        };
+        BackendHealth {
+            name: self.name.clone(),
+            namespace: self.namespace.prefix().to_string(),
+            healthy: error.is_none(),
+            error,
+        }
+    }
+}
+
+/// Prunes dead workers from every backend each `every` seconds, like running Resque's
+/// `prune_dead_workers` on a timer.
+pub fn spawn_worker_pruner(
+    backends: Vec<ConfiguredBackend>,
+    every: u64,
+    stale_after: chrono::Duration,
+) {
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(Duration::from_secs(every));
+        loop {
+            interval.tick().await;
+            for configured in backends.iter() {
+                let backend = match configured.connect().await {
+                    Ok(backend) => backend,
+                    Err(err) => {
+                        log::warn!("unable to connect to {}: {}", configured.name, err);
+                        continue;
+                    }
+                };
+                let pruned = resque::prune_dead_workers(
+                    backend.redis.clone(),
+                    &backend.namespace,
+                    stale_after,
+                )
+                .await;
+                match pruned {
+                    Ok(ids) if !ids.is_empty() => {
+                        log::info!(
+                            "pruned dead workers on {}: {}",
+                            backend.name,
+                            ids.join(", ")
+                        )
+                    }
+                    Ok(_) => {}
+                    Err(err) => log::warn!("unable to prune workers on {}: {}", backend.name, err),
+                }
+            }
+        }
+    });
+}
+
+/// Runs the deferred retries on every backend as they come due, checking every `every`
+/// seconds.
+pub fn spawn_deferred_retries(state: web::Data<AppState>, every: u64) {
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(Duration::from_secs(every));
+        loop {
+            interval.tick().await;
+            for configured in state.backends.iter() {
+                match configured.connect().await {
+                    Ok(backend) => run_due_retries(&state, &backend).await,
+                    Err(err) => log::warn!("unable to connect to {}: {}", configured.name, err),
+                }
+            }
+        }
+    });
+}
+
+async fn run_due_retries(state: &AppState, backend: &Backend) {
+    let pass = resque::run_deferred_retries(
+        backend.redis.clone(),
+        &backend.namespace,
+        state.retry_mode,
+        chrono::Utc::now(),
+    )
+    .await;
+    let pass = match pass {
+        Ok(pass) => pass,
+        Err(err) => {
+            log::warn!(
+                "unable to run deferred retries on {}: {}",
+                backend.name,
+                err
+            );
+            return;
+        }
+    };
+    for member in pass.invalid {
+        log::warn!(
+            "set aside unreadable deferred retry on {}: {}",
+            backend.name,
+            member
+        );
+    }
+    for run in pass.runs {
+        match run.result {
+            Ok(retried) => {
+                log::info!(
+                    "deferred retry {} on {} retried {} jobs",
+                    run.retry.id,
+                    backend.name,
+                    retried
+                );
+                if let Some(action) = retry_action(&run.retry.target, retried) {
+                    state.plugins.post_action(action);
+                }
+            }
+            Err(err) => log::warn!(
+                "deferred retry {} on {} failed: {}",
+                run.retry.id,
+                backend.name,
+                err
+            ),
+        }
+    }
+}
+
+// The action the retry would have posted had it run straight away. Single job retries
+// don't post one and an empty filter is only held by retry all.
+fn retry_action(target: &resque::RetryTarget, retried: u64) -> Option<Action> {
+    match target {
+        resque::RetryTarget::Job { .. } => None,
+        resque::RetryTarget::Matching { filter } if filter.is_empty() => Some(Action::RetryAll),
+        resque::RetryTarget::Matching { .. } => Some(Action::RetryMatching(retried)),
+    }
+}
+
+fn select_backend(req: &HttpRequest) -> actix_web::Result<ConfiguredBackend> {
+    let state = req
+        .app_data::<web::Data<AppState>>()
+        .ok_or_else(|| error::ErrorInternalServerError("application state missing"))?;
+    let requested = match req.headers().get(BACKEND_HEADER) {
+        Some(val) => Some(val.to_str().map_err(error::ErrorBadRequest)?),
+        None => None,
+    };
+    let backend = match requested {
+        Some(name) => state.backends.iter().find(|backend| backend.name == name),
+        None => state.backends.first(),
+    };
+    backend
+        .cloned()
+        .ok_or_else(|| error::ErrorNotFound(format!("unknown backend {}", requested.unwrap_or(""))))
+}
+
+impl FromRequest for Backend {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut dev::Payload) -> Self::Future {
+        let selected = select_backend(req);
+        Box::pin(async move {
+            let configured = selected?;
+            configured.connect().await.map_err(|err| {
+                error::ErrorServiceUnavailable(format!(
+                    "backend {} is unavailable: {}",
+                    configured.name, err
+                ))
+            })
+        })
+    }
+}
@@ -1,20 +1,23 @@
+use crate::backend::{Backend, ConfiguredBackend};
+use crate::operations;
 use crate::resque;
 use actix_files as fs;
 use actix_web::http::StatusCode;
-use actix_web::{delete, error, get, post, web, HttpRequest, HttpResponse};
+use actix_web::{delete, error, get, post, put, web, HttpRequest, HttpResponse};
 use plugin_manager::Action;
-use redis::aio::ConnectionManager;
 use serde_derive::{Deserialize, Serialize};
 use std::path::Path;
 
 pub struct AppState {
-    pub redis: ConnectionManager,
+    pub backends: Vec<ConfiguredBackend>,
     pub plugins: plugin_manager::PluginManager,
+    pub stale_after: chrono::Duration,
+    pub retry_mode: resque::RetryMode,
 }
 
 #[derive(Serialize)]
 struct FailedJobs {
-    jobs: serde_json::Value,
+    jobs: Vec<resque::FailedEntry>,
     total_failed: u64,
 }
 
@@ -29,17 +32,103 @@ struct ResqueWorkers {
 }
 
 #[derive(Deserialize)]
-struct DeleteFailedParam {
+struct JobIdParam {
     id: String,
 }
 
+#[derive(Deserialize)]
+struct BackgroundParam {
+    #[serde(default)]
+    background: bool,
+}
+
+// Without `remove` only the queue's jobs are deleted, with it the queue is unregistered
+// as well. `force` removes it even while workers are started with it.
+#[derive(Deserialize)]
+struct DeleteQueueParam {
+    #[serde(default)]
+    remove: bool,
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Deserialize)]
+struct EditedRetryParam {
+    id: String,
+    queue: Option<String>,
+    payload: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct BulkFailedParam {
+    #[serde(flatten)]
+    filter: resque::FailureFilter,
+    #[serde(default)]
+    dry_run: bool,
+    // Whatever the fields above didn't take.
+    #[serde(flatten)]
+    unknown: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct RetryMatchingParam {
+    #[serde(flatten)]
+    filter: resque::FailureFilter,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(flatten)]
+    when: resque::RetryTime,
+    #[serde(flatten)]
+    unknown: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct PauseParam {
+    expires_in: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct MoveJobParam {
+    id: String,
+    queue: String,
+}
+
+#[derive(Deserialize)]
+struct TimestampParam {
+    from_timestamp: Option<isize>,
+}
+
+#[derive(Deserialize)]
+struct ClassParam {
+    class: String,
+}
+
+#[derive(Deserialize)]
+struct RetryFailedParam {
+    id: String,
+    queue: Option<String>,
+    #[serde(flatten)]
+    when: resque::RetryTime,
+}
+
 fn resque_error_map<T>(err: T) -> error::InternalError<T> {
     error::InternalError::new(err, StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+impl error::ResponseError for resque::ResqueError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            resque::ResqueError::NotFound(_) => StatusCode::NOT_FOUND,
+            resque::ResqueError::Conflict(_) => StatusCode::CONFLICT,
+            resque::ResqueError::Invalid(_) => StatusCode::BAD_REQUEST,
+            resque::ResqueError::Redis(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 #[get("/stats")]
-async fn resque_stats(state: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
-    let response = resque::queue_stats(state.redis.clone())
+async fn resque_stats(backend: Backend) -> actix_web::Result<HttpResponse> {
+    let response = resque::queue_stats(backend.redis.clone(), &backend.namespace)
         .await
         .map_err(resque_error_map)?;
     Ok(HttpResponse::Ok().json(&response))
@@ -49,35 +138,42 @@ async fn resque_stats(state: web::Data<AppState>) -> actix_web::Result<HttpRespo
 async fn queue_details(
     query: web::Query<JobParam>,
     path: web::Path<(String,)>,
-    state: web::Data<AppState>,
+    backend: Backend,
 ) -> actix_web::Result<HttpResponse> {
     let start_at = query.from_job.unwrap_or(0);
-    let results = resque::queue_details(state.redis.clone(), &path.0, start_at, start_at + 9)
-        .await
-        .map_err(resque_error_map)?;
+    let results = resque::queue_details(
+        backend.redis.clone(),
+        &backend.namespace,
+        &path.0,
+        start_at,
+        start_at + 9,
+    )
+    .await
+    .map_err(resque_error_map)?;
     Ok(HttpResponse::Ok().json(&results))
 }
 
 #[get("/failed")]
 async fn failed_jobs(
     query: web::Query<JobParam>,
-    state: web::Data<AppState>,
+    backend: Backend,
 ) -> actix_web::Result<HttpResponse> {
     let start_at = query.from_job.unwrap_or(0);
-    let jobs = resque::get_failed(state.redis.clone(), start_at, start_at + 9)
-        .await
-        .map_err(resque_error_map)?
-        .into_iter()
-        .map(
-            |s| match serde_json::from_str(&s).map_err(resque_error_map) {
-                Ok(v) => v,
-                Err(_) => serde_json::json!({"error": "failed to parse job"}),
-            },
-        )
-        .collect();
+    let jobs = resque::get_failed(
+        backend.redis.clone(),
+        &backend.namespace,
+        start_at,
+        start_at + 9,
+    )
+    .await
+    .map_err(resque_error_map)?
+    .into_iter()
+    .enumerate()
+    .map(|(offset, raw)| resque::FailedEntry::new(start_at + offset as isize, raw))
+    .collect();
     let response = FailedJobs {
         jobs,
-        total_failed: resque::current_failures(state.redis.clone())
+        total_failed: resque::current_failures(backend.redis.clone(), &backend.namespace)
             .await
             .map_err(resque_error_map)?,
     };
@@ -85,9 +181,12 @@ async fn failed_jobs(
 }
 
 #[get("/active_workers")]
-async fn active_workers(state: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+async fn active_workers(
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
     let workers = ResqueWorkers {
-        data: resque::active_workers(state.redis.clone())
+        data: resque::active_workers(backend.redis.clone(), &backend.namespace, state.stale_after)
             .await
             .map_err(resque_error_map)?,
     };
@@ -95,8 +194,24 @@ async fn active_workers(state: web::Data<AppState>) -> actix_web::Result<HttpRes
 }
 
 #[delete("/failed")]
-async fn delete_failed_jobs(state: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
-    let deleted = resque::clear_queue(state.redis.clone(), "failed")
+async fn delete_failed_jobs(
+    query: web::Query<BackgroundParam>,
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    // In the background the list is deleted a batch at a time so progress can be reported.
+    if query.background {
+        let bulk = operations::BulkOperation {
+            kind: "clear_failed",
+            exclusive: false,
+            filter: resque::FailureFilter::default(),
+            action: resque::BulkAction::Delete,
+            throttle: None,
+            plugin_action: None,
+        };
+        return start_operation(state, backend, bulk).await;
+    }
+    let deleted = resque::clear_queue(backend.redis.clone(), &backend.namespace, "failed")
         .await
         .map_err(resque_error_map)?;
     Ok(HttpResponse::Ok().body(deleted.to_string()))
@@ -104,61 +219,692 @@ async fn delete_failed_jobs(state: web::Data<AppState>) -> actix_web::Result<Htt
 
 #[post("/retry_job")]
 async fn retry_failed_job(
-    job: web::Json<DeleteFailedParam>,
+    job: web::Json<RetryFailedParam>,
+    backend: Backend,
     state: web::Data<AppState>,
 ) -> actix_web::Result<HttpResponse> {
-    resque::retry_failed_job(state.redis.clone(), &job.id)
-        .await
-        .map_err(resque_error_map)?;
+    let job = job.into_inner();
+    if let Some(at) = job.when.resolve(chrono::Utc::now())? {
+        let target = resque::RetryTarget::Job {
+            job_id: job.id,
+            queue: job.queue,
+        };
+        return defer_retry(&backend, target, at).await;
+    }
+    resque::retry_failed_job(
+        backend.redis.clone(),
+        &backend.namespace,
+        &job.id,
+        job.queue.as_deref(),
+        state.retry_mode,
+    )
+    .await?;
     Ok(HttpResponse::Ok().body("job retried"))
 }
 
+#[post("/retry_job/edited")]
+async fn retry_edited_job(
+    job: web::Json<EditedRetryParam>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    let job = job.into_inner();
+    resque::retry_edited_job(
+        backend.redis.clone(),
+        &backend.namespace,
+        &job.id,
+        job.queue.as_deref(),
+        job.payload,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().body("job retried"))
+}
+
+// Holds a retry for later, answering with the retry so it can be cancelled.
+async fn defer_retry(
+    backend: &Backend,
+    target: resque::RetryTarget,
+    at: chrono::DateTime<chrono::Utc>,
+) -> actix_web::Result<HttpResponse> {
+    let retry = resque::defer_retry(backend.redis.clone(), &backend.namespace, target, at).await?;
+    Ok(HttpResponse::Accepted().json(&retry))
+}
+
 #[post("/retry_all")]
-async fn retry_all(state: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
-    resque::retry_all_jobs(state.redis.clone())
+async fn retry_all(
+    when: web::Query<resque::RetryTime>,
+    query: web::Query<BackgroundParam>,
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    if let Some(at) = when.resolve(chrono::Utc::now())? {
+        let target = resque::RetryTarget::Matching {
+            filter: resque::FailureFilter::default(),
+        };
+        return defer_retry(&backend, target, at).await;
+    }
+    if query.background {
+        let bulk = operations::BulkOperation {
+            kind: "retry_all",
+            exclusive: false,
+            filter: resque::FailureFilter::default(),
+            action: resque::BulkAction::Retry(state.retry_mode),
+            throttle: None,
+            plugin_action: Some(|_| Action::RetryAll),
+        };
+        return start_operation(state, backend, bulk).await;
+    }
+    let summary =
+        resque::retry_all_jobs(backend.redis.clone(), &backend.namespace, state.retry_mode)
+            .await
+            .map_err(resque_error_map)?;
+    state.plugins.post_action(Action::RetryAll);
+    Ok(HttpResponse::Ok().json(&summary))
+}
+
+#[post("/retry_all/throttled")]
+async fn start_throttled_retry(
+    settings: web::Query<operations::ThrottleSettings>,
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let settings = settings.into_inner();
+    if settings.jobs_per_second == 0 || settings.batch_size == 0 {
+        return Err(error::ErrorBadRequest(
+            "jobs_per_second and batch_size must be at least 1",
+        ));
+    }
+    // Two throttled retries on one backend would add their rates together.
+    let bulk = operations::BulkOperation {
+        kind: THROTTLED_RETRY,
+        exclusive: true,
+        filter: resque::FailureFilter::default(),
+        action: resque::BulkAction::Retry(state.retry_mode),
+        throttle: Some(settings),
+        plugin_action: Some(|_| Action::RetryAll),
+    };
+    start_operation(state, backend, bulk).await
+}
+
+// The throttled retry routes are kept for existing clients, they answer with the same
+// operations as the /operations routes.
+const THROTTLED_RETRY: &str = "throttled_retry_all";
+
+#[get("/retry_all/throttled")]
+async fn throttled_retries(backend: Backend) -> actix_web::Result<HttpResponse> {
+    let retries: Vec<resque::Operation> =
+        resque::operations(backend.redis.clone(), &backend.namespace)
+            .await
+            .map_err(resque_error_map)?
+            .into_iter()
+            .filter(|operation| operation.kind == THROTTLED_RETRY)
+            .collect();
+    Ok(HttpResponse::Ok().json(&retries))
+}
+
+async fn throttled_retry_operation(
+    backend: &Backend,
+    id: u64,
+) -> actix_web::Result<resque::Operation> {
+    let operation = resque::operation(backend.redis.clone(), &backend.namespace, id).await?;
+    if operation.kind != THROTTLED_RETRY {
+        return Err(error::ErrorNotFound(format!(
+            "throttled retry {} not found",
+            id
+        )));
+    }
+    Ok(operation)
+}
+
+#[get("/retry_all/throttled/{id}")]
+async fn throttled_retry(
+    path: web::Path<(u64,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    let operation = throttled_retry_operation(&backend, path.0).await?;
+    Ok(HttpResponse::Ok().json(&operation))
+}
+
+#[delete("/retry_all/throttled/{id}")]
+async fn cancel_throttled_retry(
+    path: web::Path<(u64,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    throttled_retry_operation(&backend, path.0).await?;
+    let operation =
+        resque::cancel_operation(backend.redis.clone(), &backend.namespace, path.0).await?;
+    Ok(HttpResponse::Ok().json(&operation))
+}
+
+// Hands a bulk action to the background, answering with the operation to poll.
+async fn start_operation(
+    state: web::Data<AppState>,
+    backend: Backend,
+    bulk: operations::BulkOperation,
+) -> actix_web::Result<HttpResponse> {
+    let operation = operations::spawn_bulk(state, backend, bulk).await?;
+    Ok(HttpResponse::Accepted().json(&operation))
+}
+
+#[get("/operations")]
+async fn list_operations(backend: Backend) -> actix_web::Result<HttpResponse> {
+    let operations = resque::operations(backend.redis.clone(), &backend.namespace)
         .await
         .map_err(resque_error_map)?;
-    state.plugins.post_action(Action::RetryAll);
-    Ok(HttpResponse::Ok().body("all jobs retried"))
+    Ok(HttpResponse::Ok().json(&operations))
 }
 
-#[delete("/failed_job")]
-async fn delete_failed_job(
-    job: web::Json<DeleteFailedParam>,
+#[get("/operations/{id}")]
+async fn operation_status(
+    path: web::Path<(u64,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    let operation = resque::operation(backend.redis.clone(), &backend.namespace, path.0).await?;
+    Ok(HttpResponse::Ok().json(&operation))
+}
+
+#[delete("/operations/{id}")]
+async fn cancel_operation(
+    path: web::Path<(u64,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    let operation =
+        resque::cancel_operation(backend.redis.clone(), &backend.namespace, path.0).await?;
+    Ok(HttpResponse::Ok().json(&operation))
+}
+
+#[get("/failed/search")]
+async fn search_failed(
+    filter: web::Query<resque::FailureFilter>,
+    query: web::Query<JobParam>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    let skip = query.from_job.unwrap_or(0).max(0) as usize;
+    let results =
+        resque::search_failed(backend.redis.clone(), &backend.namespace, &filter, skip, 10)
+            .await
+            .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().json(&results))
+}
+
+// A misspelt field would otherwise be dropped and leave a filter that matches every failure,
+// so the matching endpoints refuse anything they don't recognise and insist on a filter
+// before changing jobs.
+fn check_filter(
+    filter: &resque::FailureFilter,
+    unknown: &serde_json::Map<String, serde_json::Value>,
+    dry_run: bool,
+) -> actix_web::Result<()> {
+    if !unknown.is_empty() {
+        let fields: Vec<&str> = unknown.keys().map(String::as_str).collect();
+        return Err(error::ErrorBadRequest(format!(
+            "unknown filter fields: {}",
+            fields.join(", ")
+        )));
+    }
+    if filter.is_empty() && !dry_run {
+        return Err(error::ErrorBadRequest(
+            "a filter is required to change matching jobs",
+        ));
+    }
+    Ok(())
+}
+
+// A dry run answers with the jobs the filter selects instead of touching them.
+async fn preview_matching(
+    backend: &Backend,
+    filter: &resque::FailureFilter,
+) -> actix_web::Result<HttpResponse> {
+    let preview = resque::search_failed(backend.redis.clone(), &backend.namespace, filter, 0, 10)
+        .await
+        .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().json(&preview))
+}
+
+#[post("/failed/retry_matching")]
+async fn retry_matching(
+    params: web::Json<RetryMatchingParam>,
+    query: web::Query<BackgroundParam>,
+    backend: Backend,
     state: web::Data<AppState>,
 ) -> actix_web::Result<HttpResponse> {
-    resque::delete_failed_job(state.redis.clone(), &job.id)
+    check_filter(&params.filter, &params.unknown, params.dry_run)?;
+    if params.dry_run {
+        return preview_matching(&backend, &params.filter).await;
+    }
+    if let Some(at) = params.when.resolve(chrono::Utc::now())? {
+        let target = resque::RetryTarget::Matching {
+            filter: params.filter.clone(),
+        };
+        return defer_retry(&backend, target, at).await;
+    }
+    if query.background {
+        let bulk = operations::BulkOperation {
+            kind: "retry_matching",
+            exclusive: false,
+            filter: params.filter.clone(),
+            action: resque::BulkAction::Retry(state.retry_mode),
+            throttle: None,
+            plugin_action: Some(Action::RetryMatching),
+        };
+        return start_operation(state, backend, bulk).await;
+    }
+    let outcome = resque::retry_matching(
+        backend.redis.clone(),
+        &backend.namespace,
+        &params.filter,
+        state.retry_mode,
+    )
+    .await
+    .map_err(resque_error_map)?;
+    state
+        .plugins
+        .post_action(Action::RetryMatching(outcome.affected));
+    Ok(HttpResponse::Ok().json(&outcome))
+}
+
+#[post("/failed/delete_matching")]
+async fn delete_matching(
+    params: web::Json<BulkFailedParam>,
+    query: web::Query<BackgroundParam>,
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    check_filter(&params.filter, &params.unknown, params.dry_run)?;
+    if params.dry_run {
+        return preview_matching(&backend, &params.filter).await;
+    }
+    if query.background {
+        let bulk = operations::BulkOperation {
+            kind: "delete_matching",
+            exclusive: false,
+            filter: params.filter.clone(),
+            action: resque::BulkAction::Delete,
+            throttle: None,
+            plugin_action: Some(Action::DeleteMatching),
+        };
+        return start_operation(state, backend, bulk).await;
+    }
+    let outcome =
+        resque::delete_matching(backend.redis.clone(), &backend.namespace, &params.filter)
+            .await
+            .map_err(resque_error_map)?;
+    state
+        .plugins
+        .post_action(Action::DeleteMatching(outcome.affected));
+    Ok(HttpResponse::Ok().json(&outcome))
+}
+
+#[get("/failed/groups")]
+async fn failure_groups(backend: Backend) -> actix_web::Result<HttpResponse> {
+    let groups = resque::failure_groups(backend.redis.clone(), &backend.namespace)
         .await
         .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().json(&groups))
+}
+
+fn group_filter(fingerprint: &str) -> resque::FailureFilter {
+    resque::FailureFilter {
+        fingerprint: Some(fingerprint.to_string()),
+        ..Default::default()
+    }
+}
+
+#[post("/failed/groups/{fingerprint}/retry")]
+async fn retry_group(
+    when: web::Query<resque::RetryTime>,
+    path: web::Path<(String,)>,
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    if let Some(at) = when.resolve(chrono::Utc::now())? {
+        let target = resque::RetryTarget::Matching {
+            filter: group_filter(&path.0),
+        };
+        return defer_retry(&backend, target, at).await;
+    }
+    let outcome = resque::retry_matching(
+        backend.redis.clone(),
+        &backend.namespace,
+        &group_filter(&path.0),
+        state.retry_mode,
+    )
+    .await
+    .map_err(resque_error_map)?;
+    state
+        .plugins
+        .post_action(Action::RetryMatching(outcome.affected));
+    Ok(HttpResponse::Ok().json(&outcome))
+}
+
+#[delete("/failed/groups/{fingerprint}")]
+async fn delete_group(
+    path: web::Path<(String,)>,
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let outcome = resque::delete_matching(
+        backend.redis.clone(),
+        &backend.namespace,
+        &group_filter(&path.0),
+    )
+    .await
+    .map_err(resque_error_map)?;
+    state
+        .plugins
+        .post_action(Action::DeleteMatching(outcome.affected));
+    Ok(HttpResponse::Ok().json(&outcome))
+}
+
+#[get("/failed/deferred")]
+async fn deferred_retries(backend: Backend) -> actix_web::Result<HttpResponse> {
+    let retries = resque::deferred_retries(backend.redis.clone(), &backend.namespace)
+        .await
+        .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().json(&retries))
+}
+
+#[delete("/failed/deferred/{id}")]
+async fn cancel_deferred_retry(
+    path: web::Path<(u64,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    let retry =
+        resque::cancel_deferred_retry(backend.redis.clone(), &backend.namespace, path.0).await?;
+    Ok(HttpResponse::Ok().json(&retry))
+}
+
+#[delete("/failed_job")]
+async fn delete_failed_job(
+    job: web::Json<JobIdParam>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    resque::delete_failed_job(backend.redis.clone(), &backend.namespace, &job.id).await?;
     Ok(HttpResponse::Ok().body("job removed"))
 }
 
 #[delete("/queue/{name}")]
 async fn delete_queue_contents(
+    query: web::Query<DeleteQueueParam>,
+    path: web::Path<(String,)>,
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let deleted = if query.remove {
+        resque::remove_queue(
+            backend.redis.clone(),
+            &backend.namespace,
+            &path.0,
+            query.force,
+        )
+        .await?
+        .to_string()
+    } else {
+        let queue_key = format!("queue:{}", path.0);
+        resque::clear_queue(backend.redis.clone(), &backend.namespace, &queue_key)
+            .await
+            .map_err(resque_error_map)?
+            .to_string()
+    };
+    state
+        .plugins
+        .post_action(Action::DeleteQueue(path.0.clone()));
+    Ok(HttpResponse::Ok().body(deleted))
+}
+
+#[get("/queues/orphaned")]
+async fn orphaned_queues(backend: Backend) -> actix_web::Result<HttpResponse> {
+    let queues = resque::orphaned_queues(backend.redis.clone(), &backend.namespace)
+        .await
+        .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().json(&queues))
+}
+
+#[post("/queue/{name}/pause")]
+async fn pause_queue(
+    query: web::Query<PauseParam>,
     path: web::Path<(String,)>,
+    backend: Backend,
     state: web::Data<AppState>,
 ) -> actix_web::Result<HttpResponse> {
-    let queue_key = format!("queue:{}", path.0);
-    let deleted = resque::clear_queue(state.redis.clone(), &queue_key)
+    resque::pause_queue(
+        backend.redis.clone(),
+        &backend.namespace,
+        &path.0,
+        query.expires_in,
+    )
+    .await?;
+    state
+        .plugins
+        .post_action(Action::PauseQueue(path.0.clone(), query.expires_in));
+    Ok(HttpResponse::Ok().body("queue paused"))
+}
+
+#[delete("/queue/{name}/pause")]
+async fn resume_queue(
+    path: web::Path<(String,)>,
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let resumed = resque::resume_queue(backend.redis.clone(), &backend.namespace, &path.0)
         .await
         .map_err(resque_error_map)?;
+    if !resumed {
+        return Err(error::ErrorNotFound(format!(
+            "queue {} is not paused",
+            path.0
+        )));
+    }
     state
         .plugins
-        .post_action(Action::DeleteQueue(path.0.clone()));
-    Ok(HttpResponse::Ok().body(deleted.to_string()))
+        .post_action(Action::ResumeQueue(path.0.clone()));
+    Ok(HttpResponse::Ok().body("queue resumed"))
+}
+
+#[post("/queue/{name}/jobs")]
+async fn enqueue_job(
+    path: web::Path<(String,)>,
+    job: web::Json<resque::JobPayload>,
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let enqueued =
+        resque::enqueue_job(backend.redis.clone(), &backend.namespace, &path.0, &job).await?;
+    state.plugins.post_action(Action::EnqueueJob(
+        enqueued.queue.clone(),
+        enqueued.payload.clone(),
+    ));
+    Ok(HttpResponse::Created().json(&enqueued))
+}
+
+#[delete("/queue/{name}/jobs")]
+async fn delete_queued_job(
+    job: web::Json<JobIdParam>,
+    path: web::Path<(String,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    resque::delete_queued_job(backend.redis.clone(), &backend.namespace, &path.0, &job.id).await?;
+    Ok(HttpResponse::Ok().body("job removed"))
+}
+
+#[post("/queue/{name}/jobs/move")]
+async fn move_queued_job(
+    job: web::Json<MoveJobParam>,
+    path: web::Path<(String,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    resque::move_queued_job(
+        backend.redis.clone(),
+        &backend.namespace,
+        &path.0,
+        &job.id,
+        &job.queue,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().body("job moved"))
+}
+
+#[post("/queue/{name}/jobs/front")]
+async fn prioritize_queued_job(
+    job: web::Json<JobIdParam>,
+    path: web::Path<(String,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    resque::prioritize_queued_job(backend.redis.clone(), &backend.namespace, &path.0, &job.id)
+        .await?;
+    Ok(HttpResponse::Ok().body("job moved to front"))
 }
 
 #[delete("/worker/{id}")]
 async fn delete_worker(
     path: web::Path<(String,)>,
-    state: web::Data<AppState>,
+    backend: Backend,
 ) -> actix_web::Result<HttpResponse> {
-    resque::remove_worker(state.redis.clone(), &path.0)
+    resque::remove_worker(backend.redis.clone(), &backend.namespace, &path.0)
         .await
         .map_err(resque_error_map)?;
     Ok(HttpResponse::Ok().body("worker removed"))
 }
 
+#[post("/workers/prune")]
+async fn prune_workers(
+    backend: Backend,
+    state: web::Data<AppState>,
+) -> actix_web::Result<HttpResponse> {
+    let pruned =
+        resque::prune_dead_workers(backend.redis.clone(), &backend.namespace, state.stale_after)
+            .await
+            .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().json(&pruned))
+}
+
+#[get("/delayed")]
+async fn delayed_timestamps(
+    query: web::Query<TimestampParam>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    let start_at = query.from_timestamp.unwrap_or(0);
+    let schedule = resque::delayed_timestamps(
+        backend.redis.clone(),
+        &backend.namespace,
+        start_at,
+        start_at + 9,
+    )
+    .await
+    .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().json(&schedule))
+}
+
+#[get("/delayed/search")]
+async fn search_delayed(
+    query: web::Query<ClassParam>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    let matches = resque::search_delayed(backend.redis.clone(), &backend.namespace, &query.class)
+        .await
+        .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().json(&matches))
+}
+
+#[get("/delayed/{timestamp:\\d+}")]
+async fn delayed_jobs(
+    query: web::Query<JobParam>,
+    path: web::Path<(i64,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    let start_at = query.from_job.unwrap_or(0);
+    let jobs = resque::delayed_jobs(
+        backend.redis.clone(),
+        &backend.namespace,
+        path.0,
+        start_at,
+        start_at + 9,
+    )
+    .await
+    .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().json(&jobs))
+}
+
+#[post("/delayed/{timestamp:\\d+}/enqueue")]
+async fn enqueue_delayed_job(
+    job: web::Json<JobIdParam>,
+    path: web::Path<(i64,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    resque::enqueue_delayed_job(backend.redis.clone(), &backend.namespace, path.0, &job.id).await?;
+    Ok(HttpResponse::Ok().body("job enqueued"))
+}
+
+#[delete("/delayed/{timestamp:\\d+}/job")]
+async fn delete_delayed_job(
+    job: web::Json<JobIdParam>,
+    path: web::Path<(i64,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    resque::delete_delayed_job(backend.redis.clone(), &backend.namespace, path.0, &job.id).await?;
+    Ok(HttpResponse::Ok().body("job removed"))
+}
+
+#[delete("/delayed/{timestamp:\\d+}")]
+async fn clear_delayed_timestamp(
+    path: web::Path<(i64,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    let deleted =
+        resque::clear_delayed_timestamp(backend.redis.clone(), &backend.namespace, path.0)
+            .await
+            .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().body(deleted.to_string()))
+}
+
+#[get("/schedules")]
+async fn recurring_schedules(backend: Backend) -> actix_web::Result<HttpResponse> {
+    let schedules = resque::recurring_schedules(backend.redis.clone(), &backend.namespace)
+        .await
+        .map_err(resque_error_map)?;
+    Ok(HttpResponse::Ok().json(&schedules))
+}
+
+#[put("/schedules/{name}")]
+async fn set_schedule(
+    config: web::Json<resque::ScheduleConfig>,
+    path: web::Path<(String,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    resque::set_schedule(
+        backend.redis.clone(),
+        &backend.namespace,
+        &path.0,
+        config.into_inner(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().body("schedule saved"))
+}
+
+#[delete("/schedules/{name}")]
+async fn remove_schedule(
+    path: web::Path<(String,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    resque::remove_schedule(backend.redis.clone(), &backend.namespace, &path.0).await?;
+    Ok(HttpResponse::Ok().body("schedule removed"))
+}
+
+#[post("/schedules/{name}/enqueue")]
+async fn enqueue_schedule(
+    path: web::Path<(String,)>,
+    backend: Backend,
+) -> actix_web::Result<HttpResponse> {
+    resque::enqueue_schedule(backend.redis.clone(), &backend.namespace, &path.0).await?;
+    Ok(HttpResponse::Ok().body("job enqueued"))
+}
+
+#[get("/backends")]
+async fn list_backends(state: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let health =
+        futures_util::future::join_all(state.backends.iter().map(|backend| backend.health())).await;
+    Ok(HttpResponse::Ok().json(&health))
+}
+
 pub async fn static_assets(req: HttpRequest) -> actix_web::Result<fs::NamedFile> {
     let path: std::path::PathBuf = req
         .match_info()
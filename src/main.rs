@@ -1,6 +1,8 @@
 use actix_web::{web, App, HttpServer};
 use serde_derive::Deserialize;
+mod backend;
 mod handlers;
+mod operations;
 mod resque;
 
 #[derive(Deserialize)]
@@ -12,6 +14,37 @@ struct AppConfig {
     hostname: String,
     plugin_dir: Option<String>,
     username: Option<String>,
+    namespace: String,
+    backends: Option<Vec<BackendConfig>>,
+    stale_worker_seconds: i64,
+    prune_interval: Option<u64>,
+    retry_mode: resque::RetryMode,
+    deferred_retry_interval: u64,
+}
+
+/// Connection details for one of the Redis databases the server can switch between.
+#[derive(Deserialize)]
+struct BackendConfig {
+    name: String,
+    connection_string: Option<String>,
+    #[serde(default)]
+    hostname: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default)]
+    database: i64,
+    username: Option<String>,
+    password: Option<String>,
+    #[serde(default = "default_namespace")]
+    namespace: String,
+}
+
+fn default_port() -> u16 {
+    6379
+}
+
+fn default_namespace() -> String {
+    String::from("resque")
 }
 
 fn load_config() -> Result<AppConfig, config::ConfigError> {
@@ -20,15 +53,66 @@ fn load_config() -> Result<AppConfig, config::ConfigError> {
         .set_default("hostname", String::from(""))?
         .set_default("port", 6379)?
         .set_default("database", 0)?
-        .merge(config::Environment::with_prefix("REDIS"))?;
+        .set_default("namespace", default_namespace())?
+        .set_default("stale_worker_seconds", resque::DEFAULT_STALE_AFTER)?
+        .set_default("retry_mode", "remove")?
+        .set_default("deferred_retry_interval", 10)?;
+    if let Ok(val) = std::env::var("RESQUE_CONFIG_FILE") {
+        settings.merge(config::File::with_name(&val))?;
+    }
+    settings.merge(config::Environment::with_prefix("REDIS"))?;
     if let Ok(val) = std::env::var("RESQUE_PLUGIN_DIR") {
         settings.set("plugin_dir", val)?;
     }
-    settings.try_into::<AppConfig>()
+    if let Ok(val) = std::env::var("RESQUE_STALE_WORKER_SECONDS") {
+        settings.set("stale_worker_seconds", val)?;
+    }
+    if let Ok(val) = std::env::var("RESQUE_PRUNE_INTERVAL") {
+        settings.set("prune_interval", val)?;
+    }
+    if let Ok(val) = std::env::var("RESQUE_RETRY_MODE") {
+        settings.set("retry_mode", val)?;
+    }
+    if let Ok(val) = std::env::var("RESQUE_DEFERRED_RETRY_INTERVAL") {
+        settings.set("deferred_retry_interval", val)?;
+    }
+    let config = settings.try_into::<AppConfig>()?;
+    // The background timers can't run on a zero period, so refuse it before starting.
+    if config.prune_interval == Some(0) {
+        return Err(config::ConfigError::Message(String::from(
+            "RESQUE_PRUNE_INTERVAL must be at least 1 second",
+        )));
+    }
+    if config.deferred_retry_interval == 0 {
+        return Err(config::ConfigError::Message(String::from(
+            "RESQUE_DEFERRED_RETRY_INTERVAL must be at least 1 second",
+        )));
+    }
+    Ok(config)
+}
+
+impl AppConfig {
+    // Without a list of backends the top level connection settings describe a single
+    // backend named "default".
+    fn backend_configs(&mut self) -> Vec<BackendConfig> {
+        match self.backends.take() {
+            Some(backends) if !backends.is_empty() => backends,
+            _ => vec![BackendConfig {
+                name: String::from("default"),
+                connection_string: self.connection_string.clone(),
+                hostname: self.hostname.clone(),
+                port: self.port,
+                database: self.database,
+                username: self.username.clone(),
+                password: self.password.clone(),
+                namespace: self.namespace.clone(),
+            }],
+        }
+    }
 }
 
-impl From<&AppConfig> for redis::ConnectionInfo {
-    fn from(config: &AppConfig) -> Self {
+impl From<&BackendConfig> for redis::ConnectionInfo {
+    fn from(config: &BackendConfig) -> Self {
         redis::ConnectionInfo {
             addr: Box::new(redis::ConnectionAddr::Tcp(
                 config.hostname.clone(),
@@ -41,17 +125,30 @@ impl From<&AppConfig> for redis::ConnectionInfo {
     }
 }
 
-async fn open_redis(
-    config: &AppConfig,
-) -> Result<redis::aio::ConnectionManager, Box<dyn std::error::Error>> {
-    let client = match &config.connection_string {
+fn redis_client(config: &BackendConfig) -> redis::RedisResult<redis::Client> {
+    match &config.connection_string {
         Some(val) => redis::Client::open(val.as_ref()),
         None => redis::Client::open(redis::ConnectionInfo::from(config)),
-    };
-    client?
-        .get_tokio_connection_manager()
-        .await
-        .map_err(|e| e.into())
+    }
+}
+
+// Connections are opened when a backend is first used, so a backend that's down at startup
+// is reported as unhealthy instead of keeping the server from starting.
+fn configure_backends(
+    configs: Vec<BackendConfig>,
+) -> Result<Vec<backend::ConfiguredBackend>, Box<dyn std::error::Error>> {
+    let mut backends: Vec<backend::ConfiguredBackend> = Vec::with_capacity(configs.len());
+    for config in configs.iter() {
+        if backends.iter().any(|backend| backend.name == config.name) {
+            return Err(format!("duplicate backend name {}", config.name).into());
+        }
+        backends.push(backend::ConfiguredBackend::new(
+            config.name.clone(),
+            redis_client(config)?,
+            resque::Namespace::new(&config.namespace),
+        ));
+    }
+    Ok(backends)
 }
 
 fn make_plugin_manager(
@@ -68,14 +165,21 @@ fn make_plugin_manager(
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     std::env::set_var("RUST_LOG", "info");
     env_logger::init();
-    let app_config = load_config().unwrap();
-    let redis = open_redis(&app_config).await?;
+    let mut app_config = load_config().unwrap();
+    let backends = configure_backends(app_config.backend_configs())?;
     let plugin_manager = make_plugin_manager(&app_config).expect("error loading plugins");
+    let stale_after = chrono::Duration::seconds(app_config.stale_worker_seconds);
+    if let Some(seconds) = app_config.prune_interval {
+        backend::spawn_worker_pruner(backends.clone(), seconds, stale_after);
+    }
     let sub_uri = std::env::var("SUB_URI").unwrap_or_else(|_| "".to_string());
     let data = web::Data::new(handlers::AppState {
-        redis,
+        backends,
         plugins: plugin_manager,
+        stale_after,
+        retry_mode: app_config.retry_mode,
     });
+    backend::spawn_deferred_retries(data.clone(), app_config.deferred_retry_interval);
     let result = HttpServer::new(move || {
         App::new()
             .wrap(actix_web::middleware::Logger::default())
@@ -88,14 +192,49 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         web::scope("/api")
                             .service(handlers::resque_stats)
                             .service(handlers::failed_jobs)
+                            .service(handlers::search_failed)
+                            .service(handlers::retry_matching)
+                            .service(handlers::delete_matching)
+                            .service(handlers::failure_groups)
+                            .service(handlers::retry_group)
+                            .service(handlers::deferred_retries)
+                            .service(handlers::cancel_deferred_retry)
+                            .service(handlers::delete_group)
                             .service(handlers::active_workers)
                             .service(handlers::queue_details)
                             .service(handlers::delete_failed_jobs)
                             .service(handlers::delete_queue_contents)
+                            .service(handlers::orphaned_queues)
+                            .service(handlers::pause_queue)
+                            .service(handlers::resume_queue)
+                            .service(handlers::enqueue_job)
+                            .service(handlers::delete_queued_job)
+                            .service(handlers::move_queued_job)
+                            .service(handlers::prioritize_queued_job)
                             .service(handlers::delete_failed_job)
                             .service(handlers::retry_failed_job)
+                            .service(handlers::retry_edited_job)
                             .service(handlers::retry_all)
-                            .service(handlers::delete_worker),
+                            .service(handlers::start_throttled_retry)
+                            .service(handlers::throttled_retries)
+                            .service(handlers::throttled_retry)
+                            .service(handlers::cancel_throttled_retry)
+                            .service(handlers::list_operations)
+                            .service(handlers::operation_status)
+                            .service(handlers::cancel_operation)
+                            .service(handlers::delete_worker)
+                            .service(handlers::prune_workers)
+                            .service(handlers::list_backends)
+                            .service(handlers::delayed_timestamps)
+                            .service(handlers::search_delayed)
+                            .service(handlers::delayed_jobs)
+                            .service(handlers::enqueue_delayed_job)
+                            .service(handlers::delete_delayed_job)
+                            .service(handlers::clear_delayed_timestamp)
+                            .service(handlers::recurring_schedules)
+                            .service(handlers::set_schedule)
+                            .service(handlers::remove_schedule)
+                            .service(handlers::enqueue_schedule),
                     )
                     .route("{filename:.*}", web::get().to(handlers::static_assets)),
             )
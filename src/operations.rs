@@ -0,0 +1,121 @@
+use crate::backend::Backend;
+use crate::handlers::AppState;
+use crate::resque::{self, BulkAction, FailureFilter, Operation, OperationState, ResqueResult};
+use actix_web::web;
+use plugin_manager::Action;
+use serde_derive::Deserialize;
+use std::time::Duration;
+
+// Failures examined per batch when an operation isn't throttled.
+const BULK_BATCH: isize = 100;
+
+/// How quickly a throttled retry pushes failures back onto their queues.
+#[derive(Deserialize)]
+pub struct ThrottleSettings {
+    #[serde(default = "default_jobs_per_second")]
+    pub jobs_per_second: u32,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: u32,
+}
+
+fn default_jobs_per_second() -> u32 {
+    10
+}
+
+fn default_batch_size() -> u32 {
+    100
+}
+
+/// A walk over the failed list that an endpoint hands to the background instead of
+/// holding the request open for it.
+pub struct BulkOperation {
+    pub kind: &'static str,
+    /// Refuses to start while another operation of the same kind is running.
+    pub exclusive: bool,
+    pub filter: FailureFilter,
+    pub action: BulkAction,
+    /// Spaces batches out, otherwise they run back to back.
+    pub throttle: Option<ThrottleSettings>,
+    /// Reported to plugins with the number of jobs affected once the walk completes.
+    pub plugin_action: Option<fn(u64) -> Action>,
+}
+
+/// Records `bulk` as a running operation on `backend` and starts it.
+pub async fn spawn_bulk(
+    state: web::Data<AppState>,
+    backend: Backend,
+    bulk: BulkOperation,
+) -> ResqueResult<Operation> {
+    let operation = resque::start_operation(
+        backend.redis.clone(),
+        &backend.namespace,
+        bulk.kind,
+        bulk.exclusive,
+    )
+    .await?;
+    let mut running = operation.clone();
+    actix_rt::spawn(async move {
+        let result = run_bulk(&state, &backend, &mut running, bulk).await;
+        match &result {
+            Ok(_) => log::info!("operation {} on {} finished", running.id, backend.name),
+            Err(err) => log::warn!(
+                "operation {} on {} failed: {}",
+                running.id,
+                backend.name,
+                err
+            ),
+        }
+        running.finish(result.map_err(|err| err.to_string()));
+        let saved =
+            resque::save_operation(backend.redis.clone(), &backend.namespace, &mut running).await;
+        if let Err(err) = saved {
+            log::warn!(
+                "unable to record operation {} on {}: {}",
+                running.id,
+                backend.name,
+                err
+            );
+        }
+    });
+    Ok(operation)
+}
+
+async fn run_bulk(
+    state: &AppState,
+    backend: &Backend,
+    operation: &mut Operation,
+    bulk: BulkOperation,
+) -> redis::RedisResult<OperationState> {
+    let mut con = backend.redis.clone();
+    let ns = &backend.namespace;
+    let mut batches = resque::FailedBatches::start(&mut con, ns, bulk.filter, bulk.action).await?;
+    // A throttled batch never holds more than a second's worth of jobs, so the pause after
+    // it keeps the rate from being exceeded even briefly.
+    let size = bulk.throttle.as_ref().map_or(BULK_BATCH, |throttle| {
+        throttle.batch_size.min(throttle.jobs_per_second) as isize
+    });
+    let finished = loop {
+        operation.record(&batches);
+        resque::save_operation(con.clone(), ns, operation).await?;
+        if resque::cancel_requested(con.clone(), ns, operation.id).await? {
+            break OperationState::Cancelled;
+        }
+        let changed = match batches.next_batch(&mut con, ns, size).await? {
+            Some(changed) => changed,
+            None => break OperationState::Completed,
+        };
+        if let Some(throttle) = bulk.throttle.as_ref().filter(|_| changed > 0) {
+            let pause = changed as f64 / throttle.jobs_per_second as f64;
+            actix_rt::time::sleep(Duration::from_secs_f64(pause)).await;
+        }
+    };
+    operation.record(&batches);
+    // A cancelled operation still reports the jobs it changed before it stopped.
+    let affected = batches.outcome.affected;
+    if finished == OperationState::Completed || affected > 0 {
+        if let Some(plugin_action) = bulk.plugin_action {
+            state.plugins.post_action(plugin_action(affected));
+        }
+    }
+    Ok(finished)
+}
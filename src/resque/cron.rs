@@ -0,0 +1,331 @@
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+
+const MONTHS: [&str; 12] = [
+    "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// A cron line in the format rufus-scheduler accepts for resque-scheduler: five fields, or
+/// six with a leading seconds field, optionally followed by a time zone. rufus-scheduler
+/// runs a line without a zone in the scheduler's local time, which isn't known here, so
+/// those are evaluated in UTC.
+#[derive(Debug, PartialEq)]
+pub struct CronSchedule {
+    seconds: u64,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+    pub timezone: Option<String>,
+    zone: Tz,
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<CronSchedule, String> {
+        let mut fields: Vec<&str> = expression.split_whitespace().collect();
+        let timezone = match fields.last() {
+            Some(last) if last.contains('/') || is_utc(last) || last.parse::<Tz>().is_ok() => {
+                fields.pop().map(String::from)
+            }
+            _ => None,
+        };
+        let zone = match timezone.as_deref() {
+            None => Tz::UTC,
+            Some(name) if is_utc(name) => Tz::UTC,
+            Some(name) => name
+                .parse()
+                .map_err(|_| format!("unsupported time zone {:?}", name))?,
+        };
+        let seconds = match fields.len() {
+            5 => 1,
+            6 => parse_field(fields.remove(0), 0, 59, &[])?,
+            _ => return Err(format!("expected 5 or 6 fields in {:?}", expression)),
+        };
+        Ok(CronSchedule {
+            seconds,
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, &MONTHS)?,
+            // Sunday can be written as either 0 or 7.
+            weekdays: fold_sunday(parse_field(fields[4], 0, 7, &WEEKDAYS)?),
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+            timezone,
+            zone,
+        })
+    }
+
+    /// The first time strictly after `after` matching the schedule in its time zone.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut day = after.with_timezone(&self.zone).date_naive();
+        // Five years covers every valid schedule, including February 29th.
+        for _ in 0..(366 * 5) {
+            if self.matches_day(day) {
+                if let Some(time) = self.first_time_on(day, after) {
+                    return Some(time);
+                }
+            }
+            day = day.succ_opt()?;
+        }
+        None
+    }
+
+    // Like Vixie cron a restricted day of month and day of week match if either does.
+    fn matches_day(&self, day: NaiveDate) -> bool {
+        if !has(self.months, day.month()) {
+            return false;
+        }
+        let by_date = has(self.days, day.day());
+        let by_weekday = has(self.weekdays, day.weekday().num_days_from_sunday());
+        if self.any_day || self.any_weekday {
+            by_date && by_weekday
+        } else {
+            by_date || by_weekday
+        }
+    }
+
+    // Like cron, a time skipped by a daylight saving change doesn't run that day and one
+    // that is repeated only runs the first time round.
+    fn first_time_on(&self, day: NaiveDate, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = after.with_timezone(&self.zone).naive_local();
+        let start = if day == local.date() {
+            local.time().num_seconds_from_midnight() + 1
+        } else {
+            0
+        };
+        (start..86400).find_map(|second| {
+            let (hour, minute, sec) = (second / 3600, second / 60 % 60, second % 60);
+            if !(has(self.hours, hour) && has(self.minutes, minute) && has(self.seconds, sec)) {
+                return None;
+            }
+            let local = day.and_hms_opt(hour, minute, sec)?;
+            let time = match self.zone.from_local_datetime(&local) {
+                LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => time,
+                LocalResult::None => return None,
+            };
+            Some(time.with_timezone(&Utc)).filter(|time| *time > after)
+        })
+    }
+}
+
+// Intervals longer than this are almost certainly a mistake and would push the next
+// run past what a timestamp can hold.
+const MAX_INTERVAL_SECONDS: f64 = 100.0 * 365.0 * 86400.0;
+
+/// Parses a rufus-scheduler interval such as `30s`, `1h30m` or `2d`. A bare number is a
+/// count of seconds.
+pub fn parse_interval(interval: &str) -> Result<Duration, String> {
+    let interval = interval.trim();
+    if let Ok(seconds) = interval.parse::<f64>() {
+        return interval_seconds(seconds, interval);
+    }
+    let mut total = 0.0;
+    let mut number = String::new();
+    for c in interval.chars() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'y' => 365.0 * 86400.0,
+            'M' => 30.0 * 86400.0,
+            'w' => 7.0 * 86400.0,
+            'd' => 86400.0,
+            'h' => 3600.0,
+            'm' => 60.0,
+            's' => 1.0,
+            _ => return Err(format!("unknown unit {:?} in {:?}", c, interval)),
+        };
+        let count: f64 = number
+            .parse()
+            .map_err(|_| format!("missing number before {:?} in {:?}", c, interval))?;
+        total += count * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(format!("invalid interval {:?}", interval));
+    }
+    interval_seconds(total, interval)
+}
+
+fn interval_seconds(seconds: f64, interval: &str) -> Result<Duration, String> {
+    if !seconds.is_finite() || seconds <= 0.0 || seconds > MAX_INTERVAL_SECONDS {
+        return Err(format!("invalid interval {:?}", interval));
+    }
+    Ok(Duration::milliseconds((seconds * 1000.0) as i64))
+}
+
+fn is_utc(zone: &str) -> bool {
+    matches!(zone, "UTC" | "GMT" | "Z" | "Etc/UTC" | "Etc/GMT")
+}
+
+fn has(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+fn fold_sunday(mask: u64) -> u64 {
+    if has(mask, 7) {
+        (mask | 1) & !(1 << 7)
+    } else {
+        mask
+    }
+}
+
+// Names are numbered from the lowest value of the field, JAN is 1 and SUN is 0.
+fn parse_value(val: &str, names: &[&str], offset: u32) -> Result<u32, String> {
+    if let Ok(number) = val.parse() {
+        return Ok(number);
+    }
+    names
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(val))
+        .map(|position| position as u32 + offset)
+        .ok_or_else(|| format!("invalid value {:?}", val))
+}
+
+// Turns one field into a bitmask of the values it allows. Supports `*`, single values,
+// ranges, steps and comma separated lists of those.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .map_err(|_| format!("invalid step in {:?}", field))?,
+            ),
+            None => (part, 1),
+        };
+        let (low, high) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((low, high)) => (
+                    parse_value(low, names, min)?,
+                    parse_value(high, names, min)?,
+                ),
+                None => {
+                    let val = parse_value(range, names, min)?;
+                    (val, if part.contains('/') { max } else { val })
+                }
+            },
+        };
+        if step == 0 || low < min || high > max || low > high {
+            return Err(format!("{:?} is out of range in {:?}", part, field));
+        }
+        for val in (low..=high).step_by(step as usize) {
+            mask |= 1 << val;
+        }
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap()
+    }
+
+    #[test]
+    fn next_run_for_daily_schedule() {
+        let cron = CronSchedule::parse("30 2 * * *").unwrap();
+        assert_eq!(
+            cron.next_after(at(2021, 3, 1, 2, 30, 0)),
+            Some(at(2021, 3, 2, 2, 30, 0))
+        );
+        assert_eq!(
+            cron.next_after(at(2021, 3, 1, 1, 0, 0)),
+            Some(at(2021, 3, 1, 2, 30, 0))
+        );
+    }
+
+    #[test]
+    fn weekdays_steps_and_names() {
+        // 2021-03-06 is a Saturday.
+        let cron = CronSchedule::parse("*/15 9-17 * * MON-FRI").unwrap();
+        assert_eq!(
+            cron.next_after(at(2021, 3, 6, 12, 0, 0)),
+            Some(at(2021, 3, 8, 9, 0, 0))
+        );
+        let sunday = CronSchedule::parse("0 0 * * 7").unwrap();
+        assert_eq!(
+            sunday.next_after(at(2021, 3, 1, 0, 0, 0)),
+            Some(at(2021, 3, 7, 0, 0, 0))
+        );
+    }
+
+    #[test]
+    fn day_of_month_or_weekday() {
+        // The 1st of the month or any Monday.
+        let cron = CronSchedule::parse("0 0 1 * 1").unwrap();
+        assert_eq!(
+            cron.next_after(at(2021, 3, 2, 0, 0, 0)),
+            Some(at(2021, 3, 8, 0, 0, 0))
+        );
+    }
+
+    #[test]
+    fn seconds_and_time_zones() {
+        let cron = CronSchedule::parse("*/10 * * * * * UTC").unwrap();
+        assert_eq!(
+            cron.next_after(at(2021, 3, 1, 0, 0, 5)),
+            Some(at(2021, 3, 1, 0, 0, 10))
+        );
+        let zoned = CronSchedule::parse("0 5 * * * America/Chicago").unwrap();
+        assert_eq!(zoned.timezone.as_deref(), Some("America/Chicago"));
+        assert_eq!(
+            zoned.next_after(at(2021, 3, 1, 0, 0, 0)),
+            Some(at(2021, 3, 1, 11, 0, 0))
+        );
+        assert!(CronSchedule::parse("0 5 * * * Mars/Olympus_Mons").is_err());
+    }
+
+    #[test]
+    fn daylight_saving_changes() {
+        // Chicago skips from 02:00 to 03:00 on 2021-03-14 and repeats 01:00 to 02:00 on
+        // 2021-11-07.
+        let skipped = CronSchedule::parse("30 2 * * * America/Chicago").unwrap();
+        assert_eq!(
+            skipped.next_after(at(2021, 3, 14, 6, 0, 0)),
+            Some(at(2021, 3, 15, 7, 30, 0))
+        );
+        let repeated = CronSchedule::parse("30 1 * * * America/Chicago").unwrap();
+        assert_eq!(
+            repeated.next_after(at(2021, 11, 7, 5, 0, 0)),
+            Some(at(2021, 11, 7, 6, 30, 0))
+        );
+        assert_eq!(
+            repeated.next_after(at(2021, 11, 7, 6, 30, 0)),
+            Some(at(2021, 11, 8, 7, 30, 0))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(CronSchedule::parse("* * *").is_err());
+        assert!(CronSchedule::parse("61 * * * *").is_err());
+        assert!(CronSchedule::parse("0 0 * * FUNDAY").is_err());
+    }
+
+    #[test]
+    fn parses_intervals() {
+        assert_eq!(parse_interval("30s"), Ok(Duration::seconds(30)));
+        assert_eq!(parse_interval("1h30m"), Ok(Duration::seconds(5400)));
+        assert_eq!(parse_interval("2d"), Ok(Duration::days(2)));
+        assert_eq!(parse_interval("90"), Ok(Duration::seconds(90)));
+        assert!(parse_interval("soon").is_err());
+        for invalid in ["-5", "0", "inf", "NaN", "1e300", "0s", "200y"] {
+            assert!(
+                parse_interval(invalid).is_err(),
+                "{} should be rejected",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use super::failure::Failure;
+use super::filter::{retry_failures, FailureFilter};
+use super::time::parse_time;
+use super::{
+    current_job_id, json_failed, locate_job, requeue_failed_job, Namespace, ResqueError,
+    ResqueResult, RetryMode,
+};
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use redis::AsyncCommands;
+use serde_derive::{Deserialize, Serialize};
+
+/// What a deferred retry acts on once it comes due.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RetryTarget {
+    /// A single failed job. It is followed by contents if the failed list shifts while
+    /// the retry is waiting.
+    Job {
+        job_id: String,
+        queue: Option<String>,
+    },
+    /// Every failure matching the filter when the retry runs.
+    Matching { filter: FailureFilter },
+}
+
+/// A retry the server is holding until `run_at`, a unix timestamp.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeferredRetry {
+    pub id: u64,
+    pub run_at: i64,
+    #[serde(flatten)]
+    pub target: RetryTarget,
+}
+
+// Retries can't be held further out than this.
+const MAX_DEFER_DAYS: i64 = 365;
+
+/// When a retry should run. `retry_in` is a number of seconds from now, `retry_at` is a
+/// time in any format Resque writes or `HH:MM` for the next time the UTC clock shows it.
+#[derive(Deserialize, Debug, Default)]
+pub struct RetryTime {
+    pub retry_at: Option<String>,
+    pub retry_in: Option<i64>,
+}
+
+/// A deferred retry that came due along with the number of jobs it retried.
+#[derive(Debug)]
+pub struct DeferredRun {
+    pub retry: DeferredRetry,
+    pub result: ResqueResult<u64>,
+}
+
+/// The retries that came due in one pass, and any held entries that couldn't be read.
+/// Those are moved to `web:deferred_retries:invalid` rather than run or thrown away.
+#[derive(Debug, Default)]
+pub struct DeferredPass {
+    pub runs: Vec<DeferredRun>,
+    pub invalid: Vec<String>,
+}
+
+impl RetryTime {
+    /// The time to retry at, or `None` when the retry should happen right away.
+    pub fn resolve(&self, now: DateTime<Utc>) -> ResqueResult<Option<DateTime<Utc>>> {
+        let at = match (self.retry_at.as_deref(), self.retry_in) {
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => {
+                return Err(ResqueError::Invalid(String::from(
+                    "only one of retry_at and retry_in can be given",
+                )))
+            }
+            (None, Some(seconds)) if seconds > 0 => Duration::try_seconds(seconds)
+                .and_then(|delay| now.checked_add_signed(delay))
+                .ok_or_else(|| {
+                    ResqueError::Invalid(format!("retry_in {} is out of range", seconds))
+                })?,
+            (None, Some(_)) => {
+                return Err(ResqueError::Invalid(String::from(
+                    "retry_in must be a positive number of seconds",
+                )))
+            }
+            (Some(val), None) => clock_time(val, now)
+                .or_else(|| parse_time(val))
+                .ok_or_else(|| ResqueError::Invalid(format!("unrecognised time {:?}", val)))?,
+        };
+        if at <= now {
+            return Err(ResqueError::Invalid(format!("{} is in the past", at)));
+        }
+        if at > now + Duration::days(MAX_DEFER_DAYS) {
+            return Err(ResqueError::Invalid(format!(
+                "{} is more than {} days away",
+                at, MAX_DEFER_DAYS
+            )));
+        }
+        Ok(Some(at))
+    }
+}
+
+// "02:00" means the next 02:00 UTC, which is tomorrow once today's has passed.
+fn clock_time(val: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let time = NaiveTime::parse_from_str(val.trim(), "%H:%M").ok()?;
+    let today = now.date_naive().and_time(time).and_utc();
+    if today > now {
+        Some(today)
+    } else {
+        Some(today + Duration::days(1))
+    }
+}
+
+fn schedule_key(ns: &Namespace) -> String {
+    ns.key("web:deferred_retries")
+}
+
+fn invalid_key(ns: &Namespace) -> String {
+    ns.key("web:deferred_retries:invalid")
+}
+
+/// Holds a retry until `run_at`. A single job is checked now so a bad id is reported
+/// straight away rather than when the retry comes due.
+pub async fn defer_retry(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    target: RetryTarget,
+    run_at: DateTime<Utc>,
+) -> ResqueResult<DeferredRetry> {
+    if let RetryTarget::Job { job_id, queue } = &target {
+        let (_, raw) = locate_job(&mut con, &ns.key("failed"), job_id).await?;
+        Failure::parse(&raw)?.retry_queue(queue.as_deref())?;
+    }
+    let id: u64 = con.incr(ns.key("web:deferred_retry_id"), 1).await?;
+    let retry = DeferredRetry {
+        id,
+        run_at: run_at.timestamp(),
+        target,
+    };
+    let member = serde_json::to_string(&retry).map_err(json_failed)?;
+    con.zadd::<_, _, _, ()>(schedule_key(ns), member, retry.run_at)
+        .await?;
+    Ok(retry)
+}
+
+/// Every retry the server is holding, soonest first.
+pub async fn deferred_retries(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+) -> redis::RedisResult<Vec<DeferredRetry>> {
+    let members: Vec<String> = con.zrange(schedule_key(ns), 0, -1).await?;
+    members
+        .iter()
+        .map(|member| serde_json::from_str(member).map_err(json_failed))
+        .collect()
+}
+
+/// Drops a held retry before it runs.
+pub async fn cancel_deferred_retry(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    id: u64,
+) -> ResqueResult<DeferredRetry> {
+    let key = schedule_key(ns);
+    let members: Vec<String> = con.zrange(&key, 0, -1).await?;
+    for member in members {
+        let retry: DeferredRetry = serde_json::from_str(&member).map_err(json_failed)?;
+        if retry.id != id {
+            continue;
+        }
+        let removed: u64 = con.zrem(&key, &member).await?;
+        if removed == 1 {
+            return Ok(retry);
+        }
+        break;
+    }
+    Err(ResqueError::NotFound(format!(
+        "deferred retry {} not found",
+        id
+    )))
+}
+
+/// Runs the retries that are due by `now`. Each one is removed from the schedule before
+/// it runs, so when several servers share a database only one of them performs it.
+pub async fn run_deferred_retries(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    mode: RetryMode,
+    now: DateTime<Utc>,
+) -> redis::RedisResult<DeferredPass> {
+    let key = schedule_key(ns);
+    let due: Vec<String> = con.zrangebyscore(&key, "-inf", now.timestamp()).await?;
+    let mut pass = DeferredPass::default();
+    for member in due {
+        let retry: DeferredRetry = match serde_json::from_str(&member) {
+            Ok(retry) => retry,
+            Err(_) => {
+                redis::pipe()
+                    .atomic()
+                    .zrem(&key, &member)
+                    .zadd(invalid_key(ns), &member, now.timestamp())
+                    .query_async::<_, ()>(&mut con)
+                    .await?;
+                pass.invalid.push(member);
+                continue;
+            }
+        };
+        let claimed: u64 = con.zrem(&key, &member).await?;
+        if claimed == 0 {
+            continue;
+        }
+        let result = match &retry.target {
+            RetryTarget::Job { job_id, queue } => {
+                retry_job(&mut con, ns, job_id, queue.as_deref(), mode).await
+            }
+            RetryTarget::Matching { filter } => retry_failures(&mut con, ns, filter, mode)
+                .await
+                .map(|outcome| outcome.affected)
+                .map_err(ResqueError::from),
+        };
+        pass.runs.push(DeferredRun { retry, result });
+    }
+    Ok(pass)
+}
+
+async fn retry_job(
+    con: &mut impl AsyncCommands,
+    ns: &Namespace,
+    id: &str,
+    queue: Option<&str>,
+    mode: RetryMode,
+) -> ResqueResult<u64> {
+    let id = current_job_id(con, &ns.key("failed"), id).await?;
+    requeue_failed_job(con, ns, &id, queue, None, mode).await?;
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::job_id;
+    use super::super::tests::mock_redis::RedisStore;
+    use super::super::tests::{command_args, MAILER_JOB};
+    use super::*;
+    use chrono::TimeZone;
+    use redis::Value;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2021, 3, 1, 12, 0, 0).unwrap()
+    }
+
+    fn when(retry_at: Option<&str>, retry_in: Option<i64>) -> RetryTime {
+        RetryTime {
+            retry_at: retry_at.map(String::from),
+            retry_in,
+        }
+    }
+
+    #[test]
+    fn resolves_retry_times() {
+        let now = now();
+        assert_eq!(when(None, None).resolve(now), Ok(None));
+        assert_eq!(
+            when(None, Some(1800)).resolve(now),
+            Ok(Some(Utc.with_ymd_and_hms(2021, 3, 1, 12, 30, 0).unwrap()))
+        );
+        assert_eq!(
+            when(Some("02:00"), None).resolve(now),
+            Ok(Some(Utc.with_ymd_and_hms(2021, 3, 2, 2, 0, 0).unwrap()))
+        );
+        assert_eq!(
+            when(Some("13:15"), None).resolve(now),
+            Ok(Some(Utc.with_ymd_and_hms(2021, 3, 1, 13, 15, 0).unwrap()))
+        );
+        assert_eq!(
+            when(Some("2021-03-05T00:00:00Z"), None).resolve(now),
+            Ok(Some(Utc.with_ymd_and_hms(2021, 3, 5, 0, 0, 0).unwrap()))
+        );
+        for invalid in [
+            when(Some("soon"), None),
+            when(Some("2021-02-01T00:00:00Z"), None),
+            when(None, Some(0)),
+            when(None, Some(100000000000000)),
+            when(None, Some(i64::MAX)),
+            when(None, Some(366 * 86400)),
+            when(Some("2100-01-01T00:00:00Z"), None),
+            when(Some("02:00"), Some(60)),
+        ] {
+            assert!(matches!(invalid.resolve(now), Err(ResqueError::Invalid(_))));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn defers_a_failed_job() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Int(1),
+                Value::Int(4),
+                Value::Data(Vec::from(MAILER_JOB)),
+            ],
+        );
+        let id = job_id(0, MAILER_JOB);
+        let target = RetryTarget::Job {
+            job_id: id.clone(),
+            queue: None,
+        };
+        let retry = defer_retry(store.clone(), &Namespace::default(), target, now())
+            .await
+            .unwrap();
+        assert_eq!(retry.id, 4);
+        assert_eq!(retry.run_at, 1614600000);
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(
+            command_args(&connection.received[1]),
+            vec!["INCRBY", "resque:web:deferred_retry_id", "1"]
+        );
+        let member = format!(
+            r#"{{"id":4,"run_at":1614600000,"kind":"job","job_id":"{}","queue":null}}"#,
+            id
+        );
+        assert_eq!(
+            command_args(&connection.received[2]),
+            vec![
+                "ZADD",
+                "resque:web:deferred_retries",
+                "1614600000",
+                member.as_str()
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn defer_rejects_missing_jobs() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Bulk(Vec::new()), Value::Nil]);
+        let target = RetryTarget::Job {
+            job_id: job_id(0, MAILER_JOB),
+            queue: None,
+        };
+        let rslt = defer_retry(store.clone(), &Namespace::default(), target, now()).await;
+        assert!(matches!(rslt, Err(ResqueError::NotFound(_))));
+        assert_eq!(store.connection.lock().unwrap().received.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn cancels_by_id() {
+        let held = r#"{"id":2,"run_at":1614600000,"kind":"matching","filter":{"class":"Job"}}"#;
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Int(1),
+                Value::Bulk(vec![Value::Data(Vec::from(held))]),
+            ],
+        );
+        let retry = cancel_deferred_retry(store.clone(), &Namespace::default(), 2)
+            .await
+            .unwrap();
+        let filter = FailureFilter {
+            class: Some(String::from("Job")),
+            ..Default::default()
+        };
+        assert_eq!(retry.target, RetryTarget::Matching { filter });
+        assert_eq!(
+            command_args(&store.connection.lock().unwrap().received[1]),
+            vec!["ZREM", "resque:web:deferred_retries", held]
+        );
+
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![Value::Bulk(vec![Value::Data(Vec::from(held))])],
+        );
+        let rslt = cancel_deferred_retry(store.clone(), &Namespace::default(), 3).await;
+        assert!(matches!(rslt, Err(ResqueError::NotFound(_))));
+    }
+
+    #[actix_rt::test]
+    async fn runs_only_claimed_retries() {
+        let id = job_id(0, MAILER_JOB);
+        let taken = r#"{"id":1,"run_at":1614599000,"kind":"matching","filter":{}}"#;
+        let held = format!(
+            r#"{{"id":2,"run_at":1614599500,"kind":"job","job_id":"{}","queue":null}}"#,
+            id
+        );
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Data(Vec::from("default")),
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Int(1),
+                Value::Int(0),
+                Value::Bulk(vec![
+                    Value::Data(Vec::from(taken)),
+                    Value::Data(held.into_bytes()),
+                ]),
+            ],
+        );
+        let runs = run_deferred_retries(
+            store.clone(),
+            &Namespace::default(),
+            RetryMode::Remove,
+            now(),
+        )
+        .await
+        .unwrap()
+        .runs;
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].retry.id, 2);
+        assert_eq!(runs[0].result, Ok(1));
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(
+            command_args(&connection.received[0]),
+            vec![
+                "ZRANGEBYSCORE",
+                "resque:web:deferred_retries",
+                "-inf",
+                "1614600000"
+            ]
+        );
+        assert_eq!(command_args(&connection.received[5])[0], "EVALSHA");
+    }
+
+    #[actix_rt::test]
+    async fn unreadable_retries_are_set_aside() {
+        let id = job_id(0, MAILER_JOB);
+        let outdated = r#"{"id":1,"run_at":1614599000,"kind":"queue","queue":"default"}"#;
+        let held = format!(
+            r#"{{"id":2,"run_at":1614599500,"kind":"job","job_id":"{}","queue":null}}"#,
+            id
+        );
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Data(Vec::from("default")),
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Int(1),
+                Value::Bulk(vec![
+                    Value::Data(Vec::from(outdated)),
+                    Value::Data(held.into_bytes()),
+                ]),
+            ],
+        )
+        .pipelined(vec![vec![Value::Bulk(vec![Value::Int(1), Value::Int(1)])]]);
+        let pass = run_deferred_retries(
+            store.clone(),
+            &Namespace::default(),
+            RetryMode::Remove,
+            now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(pass.invalid, vec![outdated]);
+        assert_eq!(pass.runs.len(), 1);
+        assert_eq!(pass.runs[0].result, Ok(1));
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(
+            command_args(&connection.received[1]),
+            vec!["ZREM", "resque:web:deferred_retries", outdated]
+        );
+        assert_eq!(
+            command_args(&connection.received[2]),
+            vec![
+                "ZADD",
+                "resque:web:deferred_retries:invalid",
+                "1614600000",
+                outdated
+            ]
+        );
+    }
+}
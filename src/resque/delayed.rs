@@ -0,0 +1,334 @@
+use super::failure::JobPayload;
+use super::{digest, json_failed, locate_job, ListEntry, Namespace, ResqueError, ResqueResult};
+use redis::AsyncCommands;
+use serde_derive::{Deserialize, Serialize};
+
+/// A job resque-scheduler is holding until its timestamp comes up. The queue is stored
+/// alongside the payload so the job can be enqueued without loading the job class.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DelayedJob {
+    pub queue: Option<String>,
+    #[serde(flatten)]
+    pub payload: JobPayload,
+}
+
+pub type DelayedEntry = ListEntry<DelayedJob>;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DelayedTimestamp {
+    timestamp: i64,
+    job_count: u64,
+}
+
+#[derive(Serialize)]
+pub struct DelayedSchedule {
+    total_timestamps: u64,
+    timestamps: Vec<DelayedTimestamp>,
+}
+
+#[derive(Serialize)]
+pub struct DelayedJobs {
+    total_jobs: u64,
+    jobs: Vec<DelayedEntry>,
+}
+
+#[derive(Serialize)]
+pub struct DelayedMatch {
+    timestamp: i64,
+    #[serde(flatten)]
+    entry: DelayedEntry,
+}
+
+const DELAYED_REMOVE: &str = include_str!("scripts/delayed_remove.lua");
+const DELAYED_CLEAR: &str = include_str!("scripts/delayed_clear.lua");
+
+fn delayed_key(ns: &Namespace, timestamp: i64) -> String {
+    ns.key(&format!("delayed:{}", timestamp))
+}
+
+pub async fn delayed_timestamps(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    start: isize,
+    end: isize,
+) -> redis::RedisResult<DelayedSchedule> {
+    let schedule = ns.key("delayed_queue_schedule");
+    let (total_timestamps, timestamps): (u64, Vec<i64>) = redis::pipe()
+        .zcard(&schedule)
+        .zrange(&schedule, start, end)
+        .query_async(&mut con)
+        .await?;
+    let counts: Vec<u64> = if timestamps.is_empty() {
+        Vec::new()
+    } else {
+        let mut pipe = redis::pipe();
+        for timestamp in timestamps.iter() {
+            pipe.llen(delayed_key(ns, *timestamp));
+        }
+        pipe.query_async(&mut con).await?
+    };
+    Ok(DelayedSchedule {
+        total_timestamps,
+        timestamps: timestamps
+            .into_iter()
+            .zip(counts)
+            .map(|(timestamp, job_count)| DelayedTimestamp {
+                timestamp,
+                job_count,
+            })
+            .collect(),
+    })
+}
+
+pub async fn delayed_jobs(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    timestamp: i64,
+    start: isize,
+    end: isize,
+) -> redis::RedisResult<DelayedJobs> {
+    let key = delayed_key(ns, timestamp);
+    let (total_jobs, jobs): (u64, Vec<String>) = redis::pipe()
+        .llen(&key)
+        .lrange(&key, start, end)
+        .query_async(&mut con)
+        .await?;
+    Ok(DelayedJobs {
+        total_jobs,
+        jobs: jobs
+            .into_iter()
+            .enumerate()
+            .map(|(offset, raw)| DelayedEntry::new(start + offset as isize, raw))
+            .collect(),
+    })
+}
+
+/// Pushes a delayed job onto its queue right away instead of waiting for its timestamp.
+pub async fn enqueue_delayed_job(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    timestamp: i64,
+    id: &str,
+) -> ResqueResult<()> {
+    let key = delayed_key(ns, timestamp);
+    let (index, raw) = locate_job(&mut con, &key, id).await?;
+    let job: DelayedJob = serde_json::from_str(&raw).map_err(json_failed)?;
+    let queue = match job.queue {
+        Some(ref queue) if !queue.is_empty() => queue.clone(),
+        _ => {
+            return Err(ResqueError::Invalid(String::from(
+                "delayed job has no queue",
+            )))
+        }
+    };
+    let payload = serde_json::to_string(&job.payload).map_err(json_failed)?;
+    let removed: Option<String> = redis::Script::new(DELAYED_REMOVE)
+        .key(&key)
+        .key(ns.key(&format!("timestamps:{}", raw)))
+        .key(ns.key("delayed_queue_schedule"))
+        .key(ns.queue(&queue))
+        .key(ns.key("queues"))
+        .arg(index)
+        .arg(digest(&raw))
+        .arg(timestamp)
+        .arg(payload)
+        .arg(queue)
+        .invoke_async(&mut con)
+        .await?;
+    removed.map(|_| ()).ok_or_else(|| super::changed_job(id))
+}
+
+pub async fn delete_delayed_job(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    timestamp: i64,
+    id: &str,
+) -> ResqueResult<()> {
+    let key = delayed_key(ns, timestamp);
+    let (index, raw) = locate_job(&mut con, &key, id).await?;
+    let removed: Option<String> = redis::Script::new(DELAYED_REMOVE)
+        .key(&key)
+        .key(ns.key(&format!("timestamps:{}", raw)))
+        .key(ns.key("delayed_queue_schedule"))
+        .arg(index)
+        .arg(digest(&raw))
+        .arg(timestamp)
+        .invoke_async(&mut con)
+        .await?;
+    removed.map(|_| ()).ok_or_else(|| super::changed_job(id))
+}
+
+/// Removes every job scheduled at `timestamp`, returning how many were removed.
+pub async fn clear_delayed_timestamp(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    timestamp: i64,
+) -> redis::RedisResult<u64> {
+    redis::Script::new(DELAYED_CLEAR)
+        .key(delayed_key(ns, timestamp))
+        .key(ns.key("delayed_queue_schedule"))
+        .arg(timestamp)
+        .arg(ns.key("timestamps:"))
+        .invoke_async(&mut con)
+        .await
+}
+
+/// Finds every delayed job of the given class across all timestamps.
+pub async fn search_delayed(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    class: &str,
+) -> redis::RedisResult<Vec<DelayedMatch>> {
+    let schedule = ns.key("delayed_queue_schedule");
+    let mut matches = Vec::new();
+    let mut start = 0;
+    loop {
+        let timestamps: Vec<i64> = con.zrange(&schedule, start, start + 99).await?;
+        if timestamps.is_empty() {
+            break;
+        }
+        let mut pipe = redis::pipe();
+        for timestamp in timestamps.iter() {
+            pipe.lrange(delayed_key(ns, *timestamp), 0, -1);
+        }
+        let lists: Vec<Vec<String>> = pipe.query_async(&mut con).await?;
+        for (timestamp, jobs) in timestamps.iter().zip(lists) {
+            for (index, raw) in jobs.into_iter().enumerate() {
+                let entry = DelayedEntry::new(index as isize, raw);
+                if entry.job.as_ref().map(|job| job.payload.class.as_str()) == Some(class) {
+                    matches.push(DelayedMatch {
+                        timestamp: *timestamp,
+                        entry,
+                    });
+                }
+            }
+        }
+        if timestamps.len() < 100 {
+            break;
+        }
+        start += 100;
+    }
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::job_id;
+    use super::super::tests::mock_redis::RedisStore;
+    use super::super::tests::{command_args, data_list};
+    use super::*;
+    use redis::Value;
+
+    const DELAYED: &str = r#"{"class":"Report","args":[1],"queue":"reports"}"#;
+
+    #[actix_rt::test]
+    async fn lists_timestamps_with_counts() {
+        let store = RedisStore::new(Vec::new(), Vec::new()).pipelined(vec![
+            vec![Value::Int(1), Value::Int(4)],
+            vec![Value::Int(3), data_list(&["1614600000", "1614603600"])],
+        ]);
+        let rslt = delayed_timestamps(store.clone(), &Namespace::default(), 0, 1)
+            .await
+            .unwrap();
+        assert_eq!(rslt.total_timestamps, 3);
+        assert_eq!(
+            rslt.timestamps,
+            vec![
+                DelayedTimestamp {
+                    timestamp: 1614600000,
+                    job_count: 1
+                },
+                DelayedTimestamp {
+                    timestamp: 1614603600,
+                    job_count: 4
+                },
+            ]
+        );
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[3]);
+        assert_eq!(args, vec!["LLEN", "resque:delayed:1614603600"]);
+    }
+
+    #[test]
+    fn delayed_job_keeps_queue_out_of_payload() {
+        let job: DelayedJob = serde_json::from_str(DELAYED).unwrap();
+        assert_eq!(job.queue.as_deref(), Some("reports"));
+        assert_eq!(
+            serde_json::to_string(&job.payload).unwrap(),
+            r#"{"class":"Report","args":[1]}"#
+        );
+    }
+
+    #[actix_rt::test]
+    async fn enqueue_delayed_job_pushes_to_queue() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Data(Vec::from(DELAYED)),
+                Value::Data(Vec::from(DELAYED)),
+            ],
+        );
+        let rslt = enqueue_delayed_job(
+            store.clone(),
+            &Namespace::default(),
+            1614600000,
+            &job_id(0, DELAYED),
+        )
+        .await;
+        assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[1]);
+        assert_eq!(args[0], "EVALSHA");
+        assert_eq!(args[2], "5");
+        assert_eq!(args[3], "resque:delayed:1614600000");
+        assert_eq!(args[4], format!("resque:timestamps:{}", DELAYED));
+        assert_eq!(args[6], "resque:queue:reports");
+        assert_eq!(args[10], "1614600000");
+        assert_eq!(args[11], r#"{"class":"Report","args":[1]}"#);
+    }
+
+    #[actix_rt::test]
+    async fn enqueue_delayed_job_without_queue_is_left_alone() {
+        let raw = r#"{"class":"Report","args":[1]}"#;
+        let store = RedisStore::new(Vec::new(), vec![Value::Data(Vec::from(raw))]);
+        let rslt =
+            enqueue_delayed_job(store.clone(), &Namespace::default(), 1, &job_id(0, raw)).await;
+        assert!(matches!(rslt, Err(ResqueError::Invalid(_))));
+        assert_eq!(store.connection.lock().unwrap().received.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn delete_delayed_job_only_cleans_up() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Data(Vec::from(DELAYED)),
+                Value::Data(Vec::from(DELAYED)),
+            ],
+        );
+        let rslt =
+            delete_delayed_job(store.clone(), &Namespace::default(), 5, &job_id(0, DELAYED)).await;
+        assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[1]);
+        assert_eq!(args[2], "3");
+        assert_eq!(args.len(), 9);
+    }
+
+    #[actix_rt::test]
+    async fn search_delayed_matches_class() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                data_list(&[DELAYED, r#"{"class":"Other","args":[]}"#, DELAYED]),
+                data_list(&["1614600000"]),
+            ],
+        );
+        let matches = search_delayed(store.clone(), &Namespace::default(), "Report")
+            .await
+            .unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].timestamp, 1614600000);
+        assert_eq!(matches[1].entry.id, job_id(2, DELAYED));
+    }
+}
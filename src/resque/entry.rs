@@ -0,0 +1,34 @@
+use serde::de::DeserializeOwned;
+use serde_derive::Serialize;
+
+/// A row of a Redis list as returned by the API. Rows that could not be parsed keep
+/// their raw contents along with the reason parsing failed.
+#[derive(Serialize, Debug)]
+pub struct ListEntry<T> {
+    pub id: String,
+    #[serde(flatten)]
+    pub job: Option<T>,
+    pub parse_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+}
+
+impl<T: DeserializeOwned> ListEntry<T> {
+    pub fn new(index: isize, raw: String) -> Self {
+        let id = super::job_id(index, &raw);
+        match serde_json::from_str::<T>(&raw) {
+            Ok(job) => ListEntry {
+                id,
+                job: Some(job),
+                parse_error: None,
+                raw: None,
+            },
+            Err(err) => ListEntry {
+                id,
+                job: None,
+                parse_error: Some(err.to_string()),
+                raw: Some(raw),
+            },
+        }
+    }
+}
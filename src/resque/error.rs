@@ -0,0 +1,32 @@
+use std::fmt;
+
+/// Errors from operations that address a specific entry in Redis or validate what is
+/// written to it. Anything else is passed through as the underlying Redis error.
+#[derive(Debug, PartialEq)]
+pub enum ResqueError {
+    Redis(redis::RedisError),
+    NotFound(String),
+    Conflict(String),
+    Invalid(String),
+}
+
+pub type ResqueResult<T> = Result<T, ResqueError>;
+
+impl fmt::Display for ResqueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResqueError::Redis(err) => err.fmt(f),
+            ResqueError::NotFound(msg) => write!(f, "not found: {}", msg),
+            ResqueError::Conflict(msg) => write!(f, "conflict: {}", msg),
+            ResqueError::Invalid(msg) => write!(f, "invalid: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ResqueError {}
+
+impl From<redis::RedisError> for ResqueError {
+    fn from(err: redis::RedisError) -> Self {
+        ResqueError::Redis(err)
+    }
+}
@@ -0,0 +1,225 @@
+use super::time::{format_time, parse_time};
+use super::{ResqueError, ResqueResult};
+use chrono::{DateTime, Utc};
+use redis::ErrorKind;
+use serde::de::{self, Deserializer};
+use serde_derive::{Deserialize, Serialize};
+
+/// The job that was being performed when a failure was recorded. Resque only defines
+/// `class` and `args`, anything else a plugin adds to the payload is kept so a retried
+/// job is pushed back exactly as it was enqueued.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobPayload {
+    pub class: String,
+    #[serde(default, deserialize_with = "args_list")]
+    pub args: Vec<serde_json::Value>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A single entry from the `resque:failed` list as written by `Resque::Failure::Redis`.
+/// Older Resque versions omit some of these fields or write them with different types
+/// so everything besides the payload is optional.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Failure {
+    #[serde(default, deserialize_with = "loose_string")]
+    pub failed_at: Option<String>,
+    #[serde(deserialize_with = "embedded_payload")]
+    pub payload: JobPayload,
+    #[serde(default, deserialize_with = "loose_string")]
+    pub exception: Option<String>,
+    #[serde(default, deserialize_with = "loose_string")]
+    pub error: Option<String>,
+    #[serde(default, deserialize_with = "backtrace_lines")]
+    pub backtrace: Vec<String>,
+    #[serde(default, deserialize_with = "loose_string")]
+    pub worker: Option<String>,
+    #[serde(default, deserialize_with = "loose_string")]
+    pub queue: Option<String>,
+    #[serde(default, deserialize_with = "loose_string")]
+    pub retried_at: Option<String>,
+    #[serde(default)]
+    pub retried_payload: Option<JobPayload>,
+}
+
+impl JobPayload {
+    /// When the job was enqueued, if it was recorded. Plugins write `enqueued_at` into the
+    /// payload itself while ActiveJob keeps it in the serialized job in the first argument.
+    pub fn enqueued_at(&self) -> Option<DateTime<Utc>> {
+        let val = self
+            .extra
+            .get("enqueued_at")
+            .or_else(|| self.args.first()?.get("enqueued_at"))?;
+        match val {
+            serde_json::Value::String(val) => parse_time(val),
+            serde_json::Value::Number(val) => parse_time(&val.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl Failure {
+    pub fn parse(raw: &str) -> redis::RedisResult<Failure> {
+        serde_json::from_str(raw).map_err(super::json_failed)
+    }
+
+    // Stamps `retried_at` onto a stored failure like `Resque::Failure.requeue`, keeping
+    // every other field as it was written. A job retried with an edited payload also
+    // records that payload next to the original.
+    pub(crate) fn mark_retried(
+        raw: &str,
+        at: DateTime<Utc>,
+        edited: Option<&JobPayload>,
+    ) -> redis::RedisResult<String> {
+        let mut entry: serde_json::Value = serde_json::from_str(raw).map_err(super::json_failed)?;
+        let fields = entry.as_object_mut().ok_or_else(|| {
+            redis::RedisError::from((ErrorKind::TypeError, "failed job is not an object"))
+        })?;
+        fields.insert(
+            String::from("retried_at"),
+            serde_json::Value::String(format_time(at)),
+        );
+        if let Some(edited) = edited {
+            let edited = serde_json::to_value(edited).map_err(super::json_failed)?;
+            fields.insert(String::from("retried_payload"), edited);
+        }
+        serde_json::to_string(&entry).map_err(super::json_failed)
+    }
+
+    // Picks the queue a retried job should be pushed to. An explicit override wins,
+    // otherwise the job goes back to the queue it originally failed in.
+    pub(crate) fn retry_queue(&self, queue: Option<&str>) -> ResqueResult<String> {
+        match queue.or(self.queue.as_deref()) {
+            Some(name) if !name.is_empty() => Ok(name.to_string()),
+            _ => Err(ResqueError::Invalid(String::from(
+                "unable to determine queue for failed job",
+            ))),
+        }
+    }
+}
+
+// Timestamps and messages have been written as strings, numbers and nulls depending on
+// the Resque version and failure backend.
+fn loose_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde::Deserialize::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(val) => Ok(Some(val)),
+        other => Ok(Some(other.to_string())),
+    }
+}
+
+// The backtrace is normally a list of frames, but some backends store a single
+// newline separated string.
+fn backtrace_lines<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde::Deserialize::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(Vec::new()),
+        serde_json::Value::String(val) => Ok(val.lines().map(String::from).collect()),
+        serde_json::Value::Array(frames) => Ok(frames
+            .into_iter()
+            .map(|frame| match frame {
+                serde_json::Value::String(val) => val,
+                other => other.to_string(),
+            })
+            .collect()),
+        other => Err(de::Error::custom(format!(
+            "unexpected backtrace: {}",
+            other
+        ))),
+    }
+}
+
+// Resque always writes args as a list, hand written jobs sometimes use a bare value.
+fn args_list<'de, D>(deserializer: D) -> Result<Vec<serde_json::Value>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde::Deserialize::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(Vec::new()),
+        serde_json::Value::Array(args) => Ok(args),
+        other => Ok(vec![other]),
+    }
+}
+
+// Some failure backends store the payload as an encoded JSON string instead of an object.
+fn embedded_payload<'de, D>(deserializer: D) -> Result<JobPayload, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde::Deserialize::deserialize(deserializer)? {
+        serde_json::Value::String(val) => serde_json::from_str(&val).map_err(de::Error::custom),
+        other => serde_json::from_value(other).map_err(de::Error::custom),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::FailedEntry;
+    use super::*;
+
+    #[test]
+    fn parses_current_resque_format() {
+        let job = Failure::parse(
+            r#"{"failed_at":"2021/03/01 12:00:00 UTC","payload":{"class":"Job","args":[1]},
+            "exception":"RuntimeError","error":"boom","backtrace":["a.rb:1"],
+            "worker":"host:1:default","queue":"default"}"#,
+        )
+        .unwrap();
+        assert_eq!(job.failed_at.as_deref(), Some("2021/03/01 12:00:00 UTC"));
+        assert_eq!(job.payload.class, "Job");
+        assert_eq!(job.payload.args, vec![serde_json::json!(1)]);
+        assert_eq!(job.backtrace, vec!["a.rb:1".to_string()]);
+        assert_eq!(job.queue.as_deref(), Some("default"));
+        assert_eq!(job.retried_at, None);
+        assert_eq!(job.retried_payload, None);
+    }
+
+    #[test]
+    fn tolerates_legacy_variants() {
+        let job = Failure::parse(
+            r#"{"failed_at":1614600000,"payload":"{\"class\":\"Job\",\"args\":\"x\",\"id\":7}",
+            "error":null,"backtrace":"a.rb:1\nb.rb:2","retried_at":null}"#,
+        )
+        .unwrap();
+        assert_eq!(job.failed_at.as_deref(), Some("1614600000"));
+        assert_eq!(job.payload.args, vec![serde_json::json!("x")]);
+        assert_eq!(job.payload.extra["id"], serde_json::json!(7));
+        assert_eq!(job.backtrace.len(), 2);
+        assert_eq!(job.error, None);
+        assert_eq!(job.worker, None);
+    }
+
+    #[test]
+    fn payload_round_trips_extra_fields() {
+        let job = Failure::parse(r#"{"payload":{"class":"Job","args":[],"jid":"abc"}}"#).unwrap();
+        let encoded = serde_json::to_value(&job.payload).unwrap();
+        assert_eq!(
+            encoded,
+            serde_json::json!({"class": "Job", "args": [], "jid": "abc"})
+        );
+    }
+
+    #[test]
+    fn missing_retry_queue_is_invalid() {
+        let job = Failure::parse(r#"{"payload":{"class":"Job","args":[]}}"#).unwrap();
+        assert_eq!(job.retry_queue(Some("mailers")).unwrap(), "mailers");
+        assert!(matches!(
+            job.retry_queue(None),
+            Err(ResqueError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn unparseable_entry_reports_diagnostic() {
+        let entry = FailedEntry::new(3, String::from(r#"{"failed_at":"now"}"#));
+        assert!(entry.id.starts_with("3-"));
+        assert!(entry.job.is_none());
+        assert!(entry.parse_error.unwrap().contains("payload"));
+        assert_eq!(entry.raw.as_deref(), Some(r#"{"failed_at":"now"}"#));
+    }
+}
@@ -0,0 +1,596 @@
+use super::failure::Failure;
+use super::groups::{fingerprint, job_class};
+use super::time::parse_time;
+use super::workers::split_id;
+use super::{digest, json_failed, FailedEntry, Namespace, ResqueError, RetryMode, RETRY_BATCH};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use serde::de::{self, Deserializer};
+use serde_derive::{Deserialize, Serialize};
+
+// Failed jobs are read from Redis this many at a time while filtering.
+const SCAN_BATCH: isize = 100;
+
+const DELETE_BATCH: &str = include_str!("scripts/delete_batch.lua");
+
+/// Narrows the failed list down. Every field that is set has to match, an empty filter
+/// matches every failure.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct FailureFilter {
+    /// The job class, or the wrapped class for ActiveJob jobs.
+    pub class: Option<String>,
+    pub exception: Option<String>,
+    /// Matches when the error message contains this text.
+    pub error: Option<String>,
+    pub queue: Option<String>,
+    pub worker_host: Option<String>,
+    #[serde(default, deserialize_with = "loose_time")]
+    pub failed_after: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "loose_time")]
+    pub failed_before: Option<DateTime<Utc>>,
+    /// Selects a group of failures from `failure_groups`.
+    pub fingerprint: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FailedSearch {
+    total_matches: u64,
+    jobs: Vec<FailedEntry>,
+}
+
+/// The result of retrying or deleting every failure matching a filter. Matching jobs that
+/// couldn't be retried, because their queue is unknown or they were already retried and
+/// kept, are counted as skipped.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct BulkOutcome {
+    pub matched: u64,
+    pub affected: u64,
+    pub skipped: u64,
+}
+
+impl FailureFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == FailureFilter::default()
+    }
+
+    pub fn matches(&self, failure: &Failure) -> bool {
+        fn equals(wanted: &Option<String>, actual: Option<&str>) -> bool {
+            wanted
+                .as_deref()
+                .is_none_or(|wanted| actual == Some(wanted))
+        }
+        let class_matches = match self.class.as_deref() {
+            Some(class) => failure.payload.class == class || job_class(failure) == class,
+            None => true,
+        };
+        let fingerprint_matches = match self.fingerprint.as_deref() {
+            Some(wanted) => fingerprint(failure) == wanted,
+            None => true,
+        };
+        let host = failure
+            .worker
+            .as_deref()
+            .and_then(|worker| split_id(worker).0);
+        let error_matches = match self.error.as_deref() {
+            Some(text) => failure.error.as_deref().is_some_and(|e| e.contains(text)),
+            None => true,
+        };
+        class_matches
+            && fingerprint_matches
+            && error_matches
+            && equals(&self.exception, failure.exception.as_deref())
+            && equals(&self.queue, failure.queue.as_deref())
+            && equals(&self.worker_host, host.as_deref())
+            && self.matches_time(failure)
+    }
+
+    fn matches_time(&self, failure: &Failure) -> bool {
+        if self.failed_after.is_none() && self.failed_before.is_none() {
+            return true;
+        }
+        match failure.failed_at.as_deref().and_then(parse_time) {
+            Some(at) => {
+                self.failed_after.is_none_or(|after| at >= after)
+                    && self.failed_before.is_none_or(|before| at <= before)
+            }
+            None => false,
+        }
+    }
+}
+
+fn loose_time<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let val: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    match val {
+        Some(val) => parse_time(&val)
+            .map(Some)
+            .ok_or_else(|| de::Error::custom(format!("unrecognised time {:?}", val))),
+        None => Ok(None),
+    }
+}
+
+/// Walks the failed list a batch at a time. Each batch is read with its own LRANGE so
+/// entries are identified by the index they had when their batch was read.
+pub(super) struct FailedScan {
+    key: String,
+    cursor: isize,
+}
+
+impl FailedScan {
+    pub(super) fn new(ns: &Namespace) -> Self {
+        FailedScan {
+            key: ns.key("failed"),
+            cursor: 0,
+        }
+    }
+
+    pub(super) async fn next_batch(
+        &mut self,
+        con: &mut impl AsyncCommands,
+    ) -> redis::RedisResult<Option<Vec<(isize, String)>>> {
+        let batch: Vec<String> = con
+            .lrange(&self.key, self.cursor, self.cursor + SCAN_BATCH - 1)
+            .await?;
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        let start = self.cursor;
+        self.cursor += batch.len() as isize;
+        Ok(Some(
+            batch
+                .into_iter()
+                .enumerate()
+                .map(|(offset, raw)| (start + offset as isize, raw))
+                .collect(),
+        ))
+    }
+}
+
+/// Finds the failures matching `filter`, returning `limit` of them starting from the
+/// `skip`th match along with how many match in total.
+pub async fn search_failed(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    filter: &FailureFilter,
+    skip: usize,
+    limit: usize,
+) -> redis::RedisResult<FailedSearch> {
+    let mut scan = FailedScan::new(ns);
+    let mut total_matches = 0;
+    let mut jobs = Vec::new();
+    while let Some(batch) = scan.next_batch(&mut con).await? {
+        for (index, raw) in batch {
+            if select(filter, &raw).is_none() {
+                continue;
+            }
+            if total_matches >= skip && jobs.len() < limit {
+                jobs.push(FailedEntry::new(index, raw));
+            }
+            total_matches += 1;
+        }
+    }
+    Ok(FailedSearch {
+        total_matches: total_matches as u64,
+        jobs,
+    })
+}
+
+// Parses an entry if the filter selects it. Unparseable entries can only be selected by
+// asking for everything, which hands back the parse error.
+fn select(filter: &FailureFilter, raw: &str) -> Option<redis::RedisResult<Failure>> {
+    match Failure::parse(raw) {
+        Ok(failure) if filter.matches(&failure) => Some(Ok(failure)),
+        Ok(_) => None,
+        Err(err) if filter.is_empty() => Some(Err(err)),
+        Err(_) => None,
+    }
+}
+
+/// What a bulk operation does to each failure its filter selects.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BulkAction {
+    Retry(RetryMode),
+    Delete,
+}
+
+/// Retries or deletes the failures a filter selects, as many at a time as the caller
+/// asks for. Only the jobs that were present when it started are examined, so failures
+/// arriving in the meantime don't keep it running forever.
+pub struct FailedBatches {
+    filter: FailureFilter,
+    action: BulkAction,
+    total: isize,
+    cursor: isize,
+    examined: isize,
+    pub outcome: BulkOutcome,
+}
+
+impl FailedBatches {
+    pub async fn start(
+        con: &mut impl AsyncCommands,
+        ns: &Namespace,
+        filter: FailureFilter,
+        action: BulkAction,
+    ) -> redis::RedisResult<Self> {
+        let total: isize = con.llen(ns.key("failed")).await?;
+        Ok(FailedBatches {
+            filter,
+            action,
+            total,
+            cursor: 0,
+            examined: 0,
+            outcome: BulkOutcome::default(),
+        })
+    }
+
+    /// How many failures were present at the start.
+    pub fn total(&self) -> u64 {
+        self.total.max(0) as u64
+    }
+
+    /// How many of the failures present at the start haven't been examined yet.
+    pub fn remaining(&self) -> u64 {
+        (self.total - self.examined).max(0) as u64
+    }
+
+    /// Examines up to `size` more failures, returning how many were retried or deleted,
+    /// or `None` once every failure has been examined.
+    pub async fn next_batch(
+        &mut self,
+        con: &mut impl AsyncCommands,
+        ns: &Namespace,
+        size: isize,
+    ) -> redis::RedisResult<Option<u64>> {
+        if self.examined >= self.total {
+            return Ok(None);
+        }
+        let key = &ns.key("failed");
+        let failed: Vec<String> = con.lrange(key, self.cursor, self.cursor + size - 1).await?;
+        if failed.is_empty() {
+            self.examined = self.total;
+            return Ok(None);
+        }
+        let changed = match self.action {
+            BulkAction::Retry(mode) => self.retry(con, ns, &failed, mode).await?,
+            BulkAction::Delete => self.delete(con, ns, &failed).await?,
+        };
+        self.outcome.affected += changed as u64;
+        self.examined += failed.len() as isize;
+        // Kept failures stay where they were so only removed ones shift the cursor.
+        self.cursor += failed.len() as isize;
+        if self.action != BulkAction::Retry(RetryMode::Keep) {
+            self.cursor -= changed;
+        }
+        Ok(Some(changed as u64))
+    }
+
+    async fn retry(
+        &mut self,
+        con: &mut impl AsyncCommands,
+        ns: &Namespace,
+        failed: &[String],
+        mode: RetryMode,
+    ) -> redis::RedisResult<isize> {
+        let now = Utc::now();
+        let key = &ns.key("failed");
+        let script = redis::Script::new(RETRY_BATCH);
+        let mut invocation = script.key(key);
+        invocation.key(ns.key("queues"));
+        let mut batched = 0;
+        for (offset, raw) in failed.iter().enumerate() {
+            let failure = match select(&self.filter, raw) {
+                Some(failure) => failure,
+                None => continue,
+            };
+            self.outcome.matched += 1;
+            // A kept failure that was already retried would only be enqueued again.
+            if mode == RetryMode::Keep && matches!(&failure, Ok(f) if f.retried_at.is_some()) {
+                self.outcome.skipped += 1;
+                continue;
+            }
+            let retry = failure.map_err(ResqueError::from).and_then(|failure| {
+                let payload = serde_json::to_string(&failure.payload).map_err(json_failed)?;
+                let replacement = mode.replacement(raw, now)?;
+                Ok((failure.retry_queue(None)?, payload, replacement))
+            });
+            match retry {
+                Ok((target, payload, replacement)) => {
+                    invocation
+                        .key(ns.queue(&target))
+                        .arg(self.cursor + offset as isize)
+                        .arg(digest(raw))
+                        .arg(payload)
+                        .arg(target)
+                        .arg(replacement);
+                    batched += 1;
+                }
+                Err(_) => self.outcome.skipped += 1,
+            }
+        }
+        if batched > 0 {
+            invocation.invoke_async(con).await
+        } else {
+            Ok(0)
+        }
+    }
+
+    async fn delete(
+        &mut self,
+        con: &mut impl AsyncCommands,
+        ns: &Namespace,
+        failed: &[String],
+    ) -> redis::RedisResult<isize> {
+        let script = redis::Script::new(DELETE_BATCH);
+        let mut invocation = script.key(ns.key("failed"));
+        let mut batched = 0;
+        for (offset, raw) in failed.iter().enumerate() {
+            if select(&self.filter, raw).is_some() {
+                invocation
+                    .arg(self.cursor + offset as isize)
+                    .arg(digest(raw));
+                batched += 1;
+            }
+        }
+        self.outcome.matched += batched;
+        if batched > 0 {
+            invocation.invoke_async(con).await
+        } else {
+            Ok(0)
+        }
+    }
+}
+
+pub(super) async fn retry_failures(
+    con: &mut impl AsyncCommands,
+    ns: &Namespace,
+    filter: &FailureFilter,
+    mode: RetryMode,
+) -> redis::RedisResult<BulkOutcome> {
+    let mut batches =
+        FailedBatches::start(con, ns, filter.clone(), BulkAction::Retry(mode)).await?;
+    while batches.next_batch(con, ns, SCAN_BATCH).await?.is_some() {}
+    Ok(batches.outcome)
+}
+
+/// Retries every failure matching `filter`.
+pub async fn retry_matching(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    filter: &FailureFilter,
+    mode: RetryMode,
+) -> redis::RedisResult<BulkOutcome> {
+    retry_failures(&mut con, ns, filter, mode).await
+}
+
+/// Deletes every failure matching `filter` from the failed list.
+pub async fn delete_matching(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    filter: &FailureFilter,
+) -> redis::RedisResult<BulkOutcome> {
+    let mut batches =
+        FailedBatches::start(&mut con, ns, filter.clone(), BulkAction::Delete).await?;
+    while batches
+        .next_batch(&mut con, ns, SCAN_BATCH)
+        .await?
+        .is_some()
+    {}
+    Ok(batches.outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::job_id;
+    use super::super::tests::mock_redis::RedisStore;
+    use super::super::tests::{command_args, data_list};
+    use super::*;
+    use redis::Value;
+
+    const BILLING_TIMEOUT: &str = r#"{"failed_at":"2021/03/01 12:00:00 UTC","payload":{"class":"BillingJob","args":[]},"exception":"Timeout::Error","error":"execution expired after 30s","worker":"web.1:12:billing","queue":"billing"}"#;
+    const BILLING_ARGUMENT: &str = r#"{"failed_at":"2021/03/01 13:00:00 UTC","payload":{"class":"BillingJob","args":[]},"exception":"ArgumentError","error":"bad account","worker":"web.2:40:billing","queue":"billing"}"#;
+    const WRAPPED_MAILER: &str = r#"{"failed_at":"2021/03/02 09:00:00 UTC","payload":{"class":"ActiveJob::QueueAdapters::ResqueAdapter::JobWrapper","args":[{"job_class":"Mailer"}]},"exception":"Timeout::Error","error":"execution expired","worker":"web.1:13:mail","queue":"mail"}"#;
+
+    // Builds a filter from `field=value&...` the way the query extractor would.
+    fn filter(query: &str) -> FailureFilter {
+        let map: serde_json::Map<String, serde_json::Value> = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, val) = pair.split_once('=').unwrap();
+                (key.to_string(), serde_json::Value::from(val))
+            })
+            .collect();
+        serde_json::from_value(serde_json::Value::Object(map)).unwrap()
+    }
+
+    fn matching(filter: &FailureFilter) -> Vec<&'static str> {
+        [BILLING_TIMEOUT, BILLING_ARGUMENT, WRAPPED_MAILER]
+            .iter()
+            .filter(|raw| filter.matches(&Failure::parse(raw).unwrap()))
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn filters_by_each_field() {
+        assert_eq!(matching(&filter("")).len(), 3);
+        assert_eq!(
+            matching(&filter("class=BillingJob&exception=Timeout::Error")),
+            vec![BILLING_TIMEOUT]
+        );
+        assert_eq!(matching(&filter("class=Mailer")), vec![WRAPPED_MAILER]);
+        assert_eq!(
+            matching(&filter("error=expired")),
+            vec![BILLING_TIMEOUT, WRAPPED_MAILER]
+        );
+        assert_eq!(matching(&filter("queue=mail")), vec![WRAPPED_MAILER]);
+        assert_eq!(
+            matching(&filter("worker_host=web.1")),
+            vec![BILLING_TIMEOUT, WRAPPED_MAILER]
+        );
+        assert_eq!(
+            matching(&filter(
+                "failed_after=2021-03-01T12:30:00Z&failed_before=2021-03-01T23:00:00Z"
+            )),
+            vec![BILLING_ARGUMENT]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_times() {
+        let query = serde_json::json!({ "failed_after": "last tuesday" });
+        assert!(serde_json::from_value::<FailureFilter>(query).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn search_pages_through_matches() {
+        let mut first: Vec<&str> = Vec::new();
+        for _ in 0..50 {
+            first.push(BILLING_TIMEOUT);
+            first.push(BILLING_ARGUMENT);
+        }
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                data_list(&[]),
+                data_list(&[WRAPPED_MAILER, "not json", BILLING_TIMEOUT]),
+                data_list(&first),
+            ],
+        );
+        let rslt = search_failed(
+            store.clone(),
+            &Namespace::default(),
+            &filter("exception=Timeout::Error"),
+            50,
+            10,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rslt.total_matches, 52);
+        let ids: Vec<&str> = rslt.jobs.iter().map(|job| job.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec![
+                job_id(100, WRAPPED_MAILER).as_str(),
+                job_id(102, BILLING_TIMEOUT).as_str()
+            ]
+        );
+        assert_eq!(store.connection.lock().unwrap().received.len(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn retries_only_matching_failures() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Int(1),
+                data_list(&[BILLING_TIMEOUT, BILLING_ARGUMENT, WRAPPED_MAILER]),
+                Value::Int(3),
+            ],
+        );
+        let rslt = retry_matching(
+            store.clone(),
+            &Namespace::default(),
+            &filter("class=BillingJob&exception=Timeout::Error"),
+            RetryMode::Remove,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            rslt,
+            BulkOutcome {
+                matched: 1,
+                affected: 1,
+                skipped: 0
+            }
+        );
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[2]);
+        assert_eq!(args[0], "EVALSHA");
+        assert_eq!(
+            &args[2..6],
+            &[
+                "3",
+                "resque:failed",
+                "resque:queues",
+                "resque:queue:billing"
+            ]
+        );
+        assert_eq!(args[6], "0");
+    }
+
+    #[actix_rt::test]
+    async fn batches_read_requested_batch_size() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Int(1),
+                data_list(&[WRAPPED_MAILER]),
+                Value::Int(2),
+                data_list(&[BILLING_TIMEOUT, BILLING_ARGUMENT]),
+                Value::Int(3),
+            ],
+        );
+        let ns = Namespace::default();
+        let mut con = store.clone();
+        let mut retry = FailedBatches::start(
+            &mut con,
+            &ns,
+            filter(""),
+            BulkAction::Retry(RetryMode::Remove),
+        )
+        .await
+        .unwrap();
+        assert_eq!(retry.remaining(), 3);
+        assert_eq!(retry.next_batch(&mut con, &ns, 2).await, Ok(Some(2)));
+        assert_eq!(retry.remaining(), 1);
+        assert_eq!(retry.next_batch(&mut con, &ns, 2).await, Ok(Some(1)));
+        assert_eq!(retry.next_batch(&mut con, &ns, 2).await, Ok(None));
+        assert_eq!(retry.outcome.affected, 3);
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(connection.received.len(), 5);
+        assert_eq!(
+            command_args(&connection.received[1]),
+            vec!["LRANGE", "resque:failed", "0", "1"]
+        );
+        // Both retried jobs were removed so the next batch starts at the front again.
+        assert_eq!(
+            command_args(&connection.received[3]),
+            vec!["LRANGE", "resque:failed", "0", "1"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn deletes_matching_failures() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Int(2),
+                data_list(&[BILLING_TIMEOUT, BILLING_ARGUMENT, WRAPPED_MAILER]),
+                Value::Int(3),
+            ],
+        );
+        let rslt = delete_matching(
+            store.clone(),
+            &Namespace::default(),
+            &filter("error=expired"),
+        )
+        .await
+        .unwrap();
+        assert_eq!((rslt.matched, rslt.affected), (2, 2));
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[2]);
+        assert_eq!(args[2], "1");
+        assert_eq!(
+            &args[4..],
+            &[
+                "0",
+                digest(BILLING_TIMEOUT).as_str(),
+                "2",
+                digest(WRAPPED_MAILER).as_str()
+            ]
+        );
+    }
+}
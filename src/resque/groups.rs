@@ -0,0 +1,233 @@
+use super::failure::Failure;
+use super::filter::FailedScan;
+use super::time::parse_time;
+use super::{digest, FailedEntry, Namespace};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+
+/// Failures sharing a fingerprint, usually many copies of the same underlying problem.
+#[derive(Serialize)]
+pub struct FailureGroup {
+    fingerprint: String,
+    class: String,
+    exception: Option<String>,
+    message: String,
+    top_frame: Option<String>,
+    count: u64,
+    first_failed_at: Option<DateTime<Utc>>,
+    last_failed_at: Option<DateTime<Utc>>,
+    sample: FailedEntry,
+}
+
+// The class that actually failed, looking through ActiveJob's wrapper.
+pub(super) fn job_class(failure: &Failure) -> &str {
+    failure
+        .payload
+        .args
+        .first()
+        .and_then(|arg| arg.get("job_class"))
+        .and_then(|class| class.as_str())
+        .unwrap_or(&failure.payload.class)
+}
+
+// Replaces the parts of an error message that change from job to job, like ids, counts
+// and quoted values, so otherwise identical messages compare equal. A single quote only
+// opens a quoted value at the start of a word, so contractions like "can't" are left alone.
+fn normalize_message(message: &str) -> String {
+    let mut normalized = String::with_capacity(message.len());
+    let mut word = String::new();
+    let mut quote = None;
+    for c in message.chars() {
+        if let Some(open) = quote {
+            if c == open {
+                normalized.push(c);
+                quote = None;
+            }
+            continue;
+        }
+        if c.is_alphanumeric() || c == '_' || c == '-' {
+            word.push(c);
+            continue;
+        }
+        let opens = c == '"' || (c == '\'' && word.is_empty());
+        flush_word(&mut normalized, &mut word);
+        normalized.push(c);
+        if opens {
+            normalized.push('?');
+            quote = Some(c);
+        }
+    }
+    flush_word(&mut normalized, &mut word);
+    normalized
+}
+
+fn flush_word(normalized: &mut String, word: &mut String) {
+    if word.chars().any(|c| c.is_ascii_digit()) {
+        normalized.push('#');
+    } else {
+        normalized.push_str(word);
+    }
+    word.clear();
+}
+
+/// Identifies failures that have the same cause: the failing class, the exception, the
+/// normalized error message and the frame the exception was raised from.
+pub fn fingerprint(failure: &Failure) -> String {
+    let message = normalize_message(failure.error.as_deref().unwrap_or(""));
+    digest(&format!(
+        "{}\n{}\n{}\n{}",
+        job_class(failure),
+        failure.exception.as_deref().unwrap_or(""),
+        message,
+        failure.backtrace.first().map(String::as_str).unwrap_or("")
+    ))
+}
+
+/// Groups the whole failed list by fingerprint, largest groups first.
+pub async fn failure_groups(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+) -> redis::RedisResult<Vec<FailureGroup>> {
+    let mut scan = FailedScan::new(ns);
+    let mut groups: HashMap<String, FailureGroup> = HashMap::new();
+    while let Some(batch) = scan.next_batch(&mut con).await? {
+        for (index, raw) in batch {
+            let failure = match Failure::parse(&raw) {
+                Ok(failure) => failure,
+                Err(_) => continue,
+            };
+            let failed_at = failure.failed_at.as_deref().and_then(parse_time);
+            let key = fingerprint(&failure);
+            let group = groups.entry(key.clone()).or_insert_with(|| FailureGroup {
+                fingerprint: key,
+                class: job_class(&failure).to_string(),
+                exception: failure.exception.clone(),
+                message: normalize_message(failure.error.as_deref().unwrap_or("")),
+                top_frame: failure.backtrace.first().cloned(),
+                count: 0,
+                first_failed_at: failed_at,
+                last_failed_at: failed_at,
+                sample: FailedEntry::new(index, raw.clone()),
+            });
+            group.count += 1;
+            if let Some(at) = failed_at {
+                group.first_failed_at =
+                    Some(group.first_failed_at.map_or(at, |first| first.min(at)));
+            }
+            group.last_failed_at = group.last_failed_at.max(failed_at);
+        }
+    }
+    let mut groups: Vec<FailureGroup> = groups.into_values().collect();
+    groups.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then(b.last_failed_at.cmp(&a.last_failed_at))
+    });
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::data_list;
+    use super::super::tests::mock_redis::RedisStore;
+    use super::*;
+
+    fn failure(error: &str, frame: &str, failed_at: &str) -> String {
+        serde_json::json!({
+            "failed_at": failed_at,
+            "payload": {"class": "BillingJob", "args": [1]},
+            "exception": "Timeout::Error",
+            "error": error,
+            "backtrace": [frame, "lib/other.rb:1"],
+            "queue": "billing"
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn normalizes_changing_values() {
+        assert_eq!(
+            normalize_message("account 4412 not found for 'acme-corp' (id=9f8e7d6c)"),
+            "account # not found for '?' (id=#)"
+        );
+        assert_eq!(normalize_message("execution expired"), "execution expired");
+    }
+
+    #[test]
+    fn contractions_are_not_quotes() {
+        assert_eq!(
+            normalize_message("Couldn't find User with 'id'=5"),
+            "Couldn't find User with '?'=#"
+        );
+        assert_ne!(
+            normalize_message("Couldn't find User with 'id'=5"),
+            normalize_message("Couldn't find Account with 'id'=5")
+        );
+        assert_eq!(
+            normalize_message("can't connect, don't retry"),
+            "can't connect, don't retry"
+        );
+    }
+
+    #[test]
+    fn fingerprint_ignores_ids_but_not_frames() {
+        let first = Failure::parse(&failure("account 1 missing", "a.rb:1", "")).unwrap();
+        let second = Failure::parse(&failure("account 2 missing", "a.rb:1", "")).unwrap();
+        let elsewhere = Failure::parse(&failure("account 2 missing", "b.rb:9", "")).unwrap();
+        assert_eq!(fingerprint(&first), fingerprint(&second));
+        assert_ne!(fingerprint(&first), fingerprint(&elsewhere));
+    }
+
+    #[actix_rt::test]
+    async fn groups_failures_by_fingerprint() {
+        let early = failure("account 1 missing", "a.rb:1", "2021/03/01 12:00:00 UTC");
+        let late = failure("account 2 missing", "a.rb:1", "2021/03/02 12:00:00 UTC");
+        let other = failure("timed out", "b.rb:9", "2021/03/01 15:00:00 UTC");
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                data_list(&[]),
+                data_list(&[&late, "not json", &other, &early]),
+            ],
+        );
+        let groups = failure_groups(store, &Namespace::default()).await.unwrap();
+        assert_eq!(groups.len(), 2);
+        let biggest = &groups[0];
+        assert_eq!(biggest.count, 2);
+        assert_eq!(biggest.message, "account # missing");
+        assert_eq!(biggest.sample.id, super::super::job_id(0, &late));
+        assert_eq!(
+            biggest.first_failed_at,
+            parse_time("2021/03/01 12:00:00 UTC")
+        );
+        assert_eq!(
+            biggest.last_failed_at,
+            parse_time("2021/03/02 12:00:00 UTC")
+        );
+        assert_eq!(groups[1].count, 1);
+    }
+
+    #[actix_rt::test]
+    async fn undated_failures_keep_the_group_times() {
+        let early = failure("account 1 missing", "a.rb:1", "2021/03/01 12:00:00 UTC");
+        let undated = failure("account 2 missing", "a.rb:1", "");
+        let late = failure("account 3 missing", "a.rb:1", "2021/03/02 12:00:00 UTC");
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![data_list(&[]), data_list(&[&late, &undated, &early])],
+        );
+        let groups = failure_groups(store, &Namespace::default()).await.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].count, 3);
+        assert_eq!(
+            groups[0].first_failed_at,
+            parse_time("2021/03/01 12:00:00 UTC")
+        );
+        assert_eq!(
+            groups[0].last_failed_at,
+            parse_time("2021/03/02 12:00:00 UTC")
+        );
+    }
+}
@@ -1,24 +1,67 @@
 use redis::{AsyncCommands, ErrorKind};
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::collections::HashSet;
 
-#[derive(Serialize)]
-pub struct Worker {
-    id: String,
-    payload: Option<String>,
-    heartbeat: Option<String>,
-}
+mod cron;
+mod deferred;
+mod delayed;
+mod entry;
+mod error;
+mod failure;
+mod filter;
+mod groups;
+mod namespace;
+mod operations;
+mod queues;
+mod schedule;
+mod time;
+mod workers;
+pub use deferred::{
+    cancel_deferred_retry, defer_retry, deferred_retries, run_deferred_retries, RetryTarget,
+    RetryTime,
+};
+pub use delayed::{
+    clear_delayed_timestamp, delayed_jobs, delayed_timestamps, delete_delayed_job,
+    enqueue_delayed_job, search_delayed,
+};
+pub use entry::ListEntry;
+pub use error::{ResqueError, ResqueResult};
+pub use failure::{Failure, JobPayload};
+pub use filter::{
+    delete_matching, retry_matching, search_failed, BulkAction, FailedBatches, FailureFilter,
+};
+pub use groups::failure_groups;
+pub use namespace::Namespace;
+pub use operations::{
+    cancel_operation, cancel_requested, operation, operations, save_operation, start_operation,
+    Operation, OperationState,
+};
+pub use queues::{
+    delete_queued_job, enqueue_job, move_queued_job, orphaned_queues, pause_queue,
+    prioritize_queued_job, remove_queue, resume_queue,
+};
+pub use schedule::{
+    enqueue_schedule, recurring_schedules, remove_schedule, set_schedule, ScheduleConfig,
+};
+pub use workers::{active_workers, prune_dead_workers, remove_worker, Worker, DEFAULT_STALE_AFTER};
+
+pub type FailedEntry = ListEntry<Failure>;
 
 #[derive(Serialize)]
 pub struct QueueDetails {
     total_jobs: u64,
-    jobs: serde_json::Value,
+    jobs: Vec<queues::QueuedEntry>,
 }
 
-#[derive(Deserialize)]
-struct FailedJob {
-    payload: serde_json::Value,
+#[derive(Serialize, Debug, PartialEq)]
+pub struct QueueStats {
+    name: String,
+    length: u64,
+    oldest_job_age: Option<i64>,
+    workers: u64,
+    unattended: bool,
+    paused: bool,
+    pause_expires_in: Option<i64>,
 }
 
 #[derive(Serialize)]
@@ -26,156 +69,356 @@ pub struct ResqueStats {
     success_count: u64,
     failure_count: u64,
     available_queues: Vec<String>,
+    queues: Vec<QueueStats>,
+}
+
+/// What happens to a failure once its job has been retried.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryMode {
+    /// Remove the failure from the failed list.
+    Remove,
+    /// Keep the failure and stamp `retried_at` on it, like `Resque::Failure.requeue`.
+    Keep,
+}
+
+impl RetryMode {
+    // The entry left in place of a retried failure, empty when it's removed.
+    fn replacement(
+        self,
+        raw: &str,
+        at: chrono::DateTime<chrono::Utc>,
+    ) -> redis::RedisResult<String> {
+        match self {
+            RetryMode::Remove => Ok(String::new()),
+            RetryMode::Keep => Failure::mark_retried(raw, at, None),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct RetryAllSummary {
+    moved: u64,
+    skipped: u64,
+    left_behind: u64,
 }
 
-pub async fn queue_stats(mut con: impl AsyncCommands) -> redis::RedisResult<ResqueStats> {
-    let (queues, fail_cnt, pass_cnt): (HashSet<String>, Option<u64>, Option<u64>) = redis::pipe()
-        .smembers("resque:queues")
-        .get("resque:stat:failed")
-        .get("resque:stat:processed")
+/// Overall counts along with the length of every queue, how long its oldest job has been
+/// waiting and how many workers listen on it. Queues holding jobs that no worker listens
+/// on are flagged as unattended.
+pub async fn queue_stats(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+) -> redis::RedisResult<ResqueStats> {
+    type Overview = (HashSet<String>, Option<u64>, Option<u64>, Vec<String>);
+    let (queues, fail_cnt, pass_cnt, workers): Overview = redis::pipe()
+        .smembers(ns.key("queues"))
+        .get(ns.key("stat:failed"))
+        .get(ns.key("stat:processed"))
+        .smembers(ns.key("workers"))
         .query_async(&mut con)
         .await?;
+    let mut queues: Vec<String> = queues.into_iter().collect();
+    queues.sort();
+    let details: Vec<(u64, Option<String>, bool, i64)> = if queues.is_empty() {
+        Vec::new()
+    } else {
+        let mut pipe = redis::pipe();
+        for queue in queues.iter() {
+            let pause = queues::pause_key(ns, queue);
+            pipe.llen(ns.queue(queue))
+                .lindex(ns.queue(queue), 0)
+                .exists(&pause)
+                .ttl(&pause);
+        }
+        pipe.query_async(&mut con).await?
+    };
+    let now = chrono::Utc::now();
+    let stats = queues
+        .iter()
+        .zip(details)
+        .map(|(name, (length, oldest, paused, ttl))| {
+            let listening = workers
+                .iter()
+                .filter(|worker| workers::listens_on(worker, name))
+                .count() as u64;
+            QueueStats {
+                name: name.clone(),
+                length,
+                oldest_job_age: oldest
+                    .and_then(|raw| serde_json::from_str::<failure::JobPayload>(&raw).ok())
+                    .and_then(|job| job.enqueued_at())
+                    .map(|at| (now - at).num_seconds()),
+                workers: listening,
+                unattended: length > 0 && listening == 0,
+                paused,
+                // TTL is negative when the key has no expiry or doesn't exist.
+                pause_expires_in: Some(ttl).filter(|ttl| paused && *ttl >= 0),
+            }
+        })
+        .collect();
     Ok(ResqueStats {
         success_count: pass_cnt.unwrap_or(0),
         failure_count: fail_cnt.unwrap_or(0),
-        available_queues: queues.into_iter().collect(),
+        available_queues: queues,
+        queues: stats,
     })
 }
 
 pub async fn get_failed(
     mut con: impl AsyncCommands,
+    ns: &Namespace,
     start: isize,
     end: isize,
 ) -> redis::RedisResult<Vec<String>> {
-    con.lrange("resque:failed", start, end).await
-}
-
-pub async fn current_failures(mut con: impl AsyncCommands) -> redis::RedisResult<u64> {
-    con.llen("resque:failed").await
+    con.lrange(ns.key("failed"), start, end).await
 }
 
-pub async fn active_workers(mut con: impl AsyncCommands) -> redis::RedisResult<Vec<Worker>> {
-    let (workers, heartbeats): (Vec<String>, HashMap<String, String>) = redis::pipe()
-        .smembers("resque:workers")
-        .hgetall("resque:workers:heartbeat")
-        .query_async(&mut con)
-        .await?;
-    let mut results = Vec::new();
-    for worker in workers.into_iter() {
-        results.push(Worker {
-            payload: con
-                .get(format!("resque:worker:{}", &worker))
-                .await
-                .unwrap_or(None),
-            heartbeat: heartbeats.get(&worker).map(|x| x.to_string()),
-            id: worker,
-        });
-    }
-    Ok(results)
+pub async fn current_failures(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+) -> redis::RedisResult<u64> {
+    con.llen(ns.key("failed")).await
 }
 
 pub async fn queue_details(
     mut con: impl AsyncCommands,
+    ns: &Namespace,
     queue_name: &str,
     start: isize,
     end: isize,
 ) -> redis::RedisResult<QueueDetails> {
-    let key = format!("resque:queue:{}", queue_name);
+    let key = ns.queue(queue_name);
     let queued_jobs: Vec<String> = con.lrange(&key, start, end).await?;
     Ok(QueueDetails {
         total_jobs: con.llen(&key).await?,
         jobs: queued_jobs
             .into_iter()
-            .map(|job| match serde_json::from_str(&job) {
-                Ok(val) => val,
-                Err(_) => serde_json::Value::Null,
-            })
+            .enumerate()
+            .map(|(offset, raw)| queues::QueuedEntry::new(start + offset as isize, raw))
             .collect(),
     })
 }
 
-pub async fn clear_queue(mut con: impl AsyncCommands, queue: &str) -> redis::RedisResult<isize> {
-    con.del(format!("resque:{}", queue)).await
+pub async fn clear_queue(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    queue: &str,
+) -> redis::RedisResult<isize> {
+    con.del(ns.key(queue)).await
 }
 
-pub async fn delete_failed_job(mut con: impl AsyncCommands, job: &str) -> redis::RedisResult<()> {
-    let key = "resque:failed";
-    remove_job(&mut con, key, job).await?;
-    Ok(())
-}
+// Failed jobs are removed by position the same way Resque::Failure.remove does, but the
+// lookup and the removal happen in one script so a concurrent request can't shift the list
+// in between.
+const REMOVE_JOB: &str = include_str!("scripts/remove_job.lua");
+const RETRY_JOB: &str = include_str!("scripts/retry_job.lua");
+const RETRY_BATCH: &str = include_str!("scripts/retry_batch.lua");
 
-pub async fn retry_failed_job(mut con: impl AsyncCommands, job: &str) -> redis::RedisResult<()> {
-    let key = "resque:failed";
-    let job = remove_job(&mut con, key, job).await?;
-    let job_payload: FailedJob = serde_json::from_str(job.as_str()).map_err(json_failed)?;
-    con.rpush("resque:queue:default", job_payload.payload.to_string())
+pub async fn delete_failed_job(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    id: &str,
+) -> ResqueResult<()> {
+    let key = &ns.key("failed");
+    let (index, failed_job) = locate_job(&mut con, key, id).await?;
+    let removed: Option<String> = redis::Script::new(REMOVE_JOB)
+        .key(key)
+        .arg(index)
+        .arg(digest(&failed_job))
+        .invoke_async(&mut con)
         .await?;
-    Ok(())
+    removed.map(|_| ()).ok_or_else(|| changed_job(id))
 }
 
-pub async fn retry_all_jobs(mut con: impl AsyncCommands) -> redis::RedisResult<()> {
-    let key = "resque:failed";
-    let mut start = 0;
-    loop {
-        let failed: Vec<String> = con.lrange(key, start, 99).await?;
-        for job in failed.iter() {
-            start += 1;
-            let job_payload: FailedJob = serde_json::from_str(job).map_err(json_failed)?;
-            con.rpush("resque:queue:default", job_payload.payload.to_string())
-                .await?;
+pub async fn retry_failed_job(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    id: &str,
+    queue: Option<&str>,
+    mode: RetryMode,
+) -> ResqueResult<()> {
+    requeue_failed_job(&mut con, ns, id, queue, None, mode).await
+}
+
+/// Retries a failed job with an edited payload. The failure is always kept, stamped with
+/// `retried_at` and the edited payload, so the original arguments aren't lost.
+pub async fn retry_edited_job(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    id: &str,
+    queue: Option<&str>,
+    payload: serde_json::Value,
+) -> ResqueResult<()> {
+    let edited = resque_payload(payload)?;
+    requeue_failed_job(&mut con, ns, id, queue, Some(&edited), RetryMode::Keep).await
+}
+
+// Checks an edited payload has the shape Resque enqueues: an object with a class name
+// and a list of arguments.
+fn resque_payload(payload: serde_json::Value) -> ResqueResult<JobPayload> {
+    let fields = payload
+        .as_object()
+        .ok_or_else(|| ResqueError::Invalid(String::from("payload must be an object")))?;
+    match fields.get("class") {
+        Some(serde_json::Value::String(class)) if !class.is_empty() => {}
+        _ => {
+            return Err(ResqueError::Invalid(String::from(
+                "payload class must be a non-empty string",
+            )))
         }
-        if failed.len() < 100 {
-            break;
+    }
+    match fields.get("args") {
+        Some(serde_json::Value::Array(_)) | None => {}
+        Some(_) => {
+            return Err(ResqueError::Invalid(String::from(
+                "payload args must be a list",
+            )))
         }
     }
-    clear_queue(con, "failed").await?;
-    Ok(())
+    serde_json::from_value(payload).map_err(|err| ResqueError::Invalid(err.to_string()))
+}
+
+async fn requeue_failed_job(
+    con: &mut impl AsyncCommands,
+    ns: &Namespace,
+    id: &str,
+    queue: Option<&str>,
+    edited: Option<&JobPayload>,
+    mode: RetryMode,
+) -> ResqueResult<()> {
+    let key = &ns.key("failed");
+    let (index, failed_job) = locate_job(con, key, id).await?;
+    let failure = Failure::parse(&failed_job)?;
+    let target = failure.retry_queue(queue)?;
+    let payload = serde_json::to_string(edited.unwrap_or(&failure.payload)).map_err(json_failed)?;
+    let script = redis::Script::new(RETRY_JOB);
+    let mut invocation = script.key(key);
+    invocation
+        .key(ns.queue(&target))
+        .key(ns.key("queues"))
+        .arg(index)
+        .arg(digest(&failed_job))
+        .arg(payload)
+        .arg(target);
+    if mode == RetryMode::Keep {
+        invocation.arg(Failure::mark_retried(
+            &failed_job,
+            chrono::Utc::now(),
+            edited,
+        )?);
+    }
+    let retried: Option<String> = invocation.invoke_async(con).await?;
+    retried.map(|_| ()).ok_or_else(|| changed_job(id))
+}
+
+pub async fn retry_all_jobs(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    mode: RetryMode,
+) -> redis::RedisResult<RetryAllSummary> {
+    let outcome = filter::retry_failures(&mut con, ns, &FailureFilter::default(), mode).await?;
+    let remaining: u64 = con.llen(ns.key("failed")).await?;
+    // Failures that were kept after being retried aren't left behind.
+    let kept = match mode {
+        RetryMode::Remove => 0,
+        RetryMode::Keep => outcome.affected,
+    };
+    Ok(RetryAllSummary {
+        moved: outcome.affected,
+        skipped: outcome.skipped,
+        left_behind: remaining.saturating_sub(outcome.skipped + kept),
+    })
 }
 
 fn json_failed(_err: serde_json::Error) -> redis::RedisError {
     std::convert::From::from((ErrorKind::IoError, "failed to parse job json"))
 }
 
-async fn remove_job(
+/// Builds the identifier for an entry in a Redis list. The index gives a fast lookup and
+/// the digest makes sure the entry at that index is still the one the caller saw.
+pub fn job_id(index: isize, raw: &str) -> String {
+    format!("{}-{}", index, digest(raw))
+}
+
+fn digest(raw: &str) -> String {
+    sha1_smol::Sha1::from(raw).digest().to_string()
+}
+
+fn parse_job_id(id: &str) -> ResqueResult<(isize, &str)> {
+    let mut parts = id.splitn(2, '-');
+    match (parts.next().map(str::parse::<isize>), parts.next()) {
+        (Some(Ok(index)), Some(digest)) if index >= 0 && digest.len() == 40 => Ok((index, digest)),
+        _ => Err(ResqueError::NotFound(format!("invalid job id {}", id))),
+    }
+}
+
+// Finds the single entry addressed by `id`. If the list shifted since the id was handed
+// out the entry is reported as moved rather than acting on a different position.
+async fn locate_job(
     con: &mut impl AsyncCommands,
     key: &str,
-    job: &str,
-) -> redis::RedisResult<String> {
+    id: &str,
+) -> ResqueResult<(isize, String)> {
+    let (index, job_digest) = parse_job_id(id)?;
+    let current: Option<String> = con.lindex(key, index).await?;
+    if let Some(raw) = current {
+        if digest(&raw) == job_digest {
+            return Ok((index, raw));
+        }
+    }
+    let current_id = relocate_job(con, key, id, job_digest).await?;
+    Err(ResqueError::Conflict(format!(
+        "job {} has moved to {}",
+        id, current_id
+    )))
+}
+
+// Follows a job to wherever it is in the list now, for callers that hold on to an id
+// for a while and only care about the job's contents.
+async fn current_job_id(con: &mut impl AsyncCommands, key: &str, id: &str) -> ResqueResult<String> {
+    let (index, job_digest) = parse_job_id(id)?;
+    let current: Option<String> = con.lindex(key, index).await?;
+    match current {
+        Some(raw) if digest(&raw) == job_digest => Ok(id.to_string()),
+        _ => relocate_job(con, key, id, job_digest).await,
+    }
+}
+
+async fn relocate_job(
+    con: &mut impl AsyncCommands,
+    key: &str,
+    id: &str,
+    job_digest: &str,
+) -> ResqueResult<String> {
+    let mut matches = Vec::new();
     let mut start = 0;
     loop {
-        let failed: Vec<String> = con.lrange(key, start, 99).await?;
-        for failed_job in failed.iter() {
-            start += 1;
-            if failed_job.contains(job) {
-                con.lrem(key, 0, failed_job.as_str()).await?;
-                return Ok(failed_job.to_string());
+        let entries: Vec<String> = con.lrange(key, start, start + 99).await?;
+        for (offset, raw) in entries.iter().enumerate() {
+            if digest(raw) == job_digest {
+                matches.push(job_id(start + offset as isize, raw));
             }
         }
-        if failed.len() < 100 {
+        if entries.len() < 100 {
             break;
         }
+        start += 100;
+    }
+    match matches.len() {
+        0 => Err(ResqueError::NotFound(format!("job {} not found", id))),
+        1 => Ok(matches.remove(0)),
+        _ => Err(ResqueError::Conflict(format!(
+            "job {} is ambiguous, matches {}",
+            id,
+            matches.join(", ")
+        ))),
     }
-
-    Err(std::convert::From::from((
-        ErrorKind::IoError,
-        "job not found",
-    )))
 }
 
-pub async fn remove_worker(mut con: impl AsyncCommands, id: &str) -> redis::RedisResult<()> {
-    redis::pipe()
-        .del(format!("resque:stat:processed:{}", id))
-        .ignore()
-        .del(format!("resque:stat:failed:{}", id))
-        .ignore()
-        .srem("resque:workers", id)
-        .ignore()
-        .hdel("resque:workers:heartbeat", id)
-        .ignore()
-        .del(format!("resque:worker:{}:started", id))
-        .ignore()
-        .query_async(&mut con)
-        .await
+// Another caller removed or replaced the entry between looking it up and acting on it.
+fn changed_job(id: &str) -> ResqueError {
+    ResqueError::Conflict(format!("job {} changed before it could be updated", id))
 }
 
 #[cfg(test)]
@@ -185,11 +428,11 @@ mod tests {
     use super::*;
     use mock_redis::RedisStore;
     use redis::Value;
-    mod mock_redis;
+    pub(crate) mod mock_redis;
     #[actix_rt::test]
     async fn test_clear_queue() {
         let store = RedisStore::new(Vec::new(), vec![Value::Int(1)]);
-        let rslt = clear_queue(store.clone(), "default").await;
+        let rslt = clear_queue(store.clone(), &Namespace::default(), "default").await;
         let connection = store.connection.lock().unwrap();
         let args: Vec<&str> = connection.received[0]
             .args_iter()
@@ -202,6 +445,44 @@ mod tests {
         assert!(args[1].contains("resque:default"));
     }
 
+    #[actix_rt::test]
+    async fn clear_queue_uses_namespace() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Int(1)]);
+        let ns = Namespace::new("resque:production");
+        clear_queue(store.clone(), &ns, "queue:default")
+            .await
+            .unwrap();
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[0]);
+        assert_eq!(args[1], "resque:production:queue:default");
+    }
+
+    #[actix_rt::test]
+    async fn remove_worker_uses_namespace() {
+        let store = RedisStore::new(Vec::new(), Vec::new());
+        let ns = Namespace::new("jobs");
+        remove_worker(store.clone(), &ns, "host:1:default")
+            .await
+            .unwrap();
+        let connection = store.connection.lock().unwrap();
+        let keys: Vec<String> = connection
+            .received
+            .iter()
+            .map(|cmd| command_args(cmd)[1].clone())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "jobs:stat:processed:host:1:default",
+                "jobs:stat:failed:host:1:default",
+                "jobs:workers",
+                "jobs:workers:heartbeat",
+                "jobs:worker:host:1:default",
+                "jobs:worker:host:1:default:started",
+            ]
+        );
+    }
+
     #[actix_rt::test]
     async fn test_get_failed() {
         let store = RedisStore::new(
@@ -211,80 +492,560 @@ mod tests {
                 Value::Data(Vec::from("failed2")),
             ])],
         );
-        let rslt = get_failed(store, 1, 100).await.unwrap();
+        let rslt = get_failed(store, &Namespace::default(), 1, 100)
+            .await
+            .unwrap();
         assert_eq!(rslt, vec!(String::from("failed1"), String::from("failed2")))
     }
 
+    pub(crate) fn command_args(cmd: &redis::Cmd) -> Vec<String> {
+        cmd.args_iter()
+            .map(|arg| match arg {
+                redis::Arg::Simple(arg) => String::from_utf8(arg.to_vec()).unwrap(),
+                _ => panic!("unexpected cursor arg"),
+            })
+            .collect()
+    }
+
+    pub(crate) const MAILER_JOB: &str =
+        r#"{"payload":{"class":"Job","args":["id1"]},"queue":"mailers"}"#;
+
     #[actix_rt::test]
     async fn delete_failed_job_succeeds() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![Value::Data(Vec::from("id2")), Value::Data(Vec::from("id2"))],
+        );
+        let rslt = delete_failed_job(store.clone(), &Namespace::default(), &job_id(1, "id2")).await;
+        assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[1]);
+        assert_eq!(args[0], "EVALSHA");
+        assert_eq!(args[3], "resque:failed");
+        assert_eq!(args[4], "1");
+        assert_eq!(args[5], digest("id2"));
+    }
+
+    #[actix_rt::test]
+    async fn delete_failed_job_queue_empties() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![Value::Bulk(vec![Value::Data(Vec::from("id1"))]), Value::Nil],
+        );
+        let rslt = delete_failed_job(store, &Namespace::default(), &job_id(4, "id2")).await;
+        match rslt {
+            Err(ResqueError::NotFound(_)) => (),
+            other => panic!("should not have found a value: {:?}", other),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn delete_failed_job_reports_moved_entry() {
         let store = RedisStore::new(
             Vec::new(),
             vec![
-                Value::Int(1),
                 Value::Bulk(vec![
                     Value::Data(Vec::from("id1")),
                     Value::Data(Vec::from("id2")),
-                    Value::Data(Vec::from("id3")),
                 ]),
+                Value::Data(Vec::from("id3")),
             ],
         );
-        let rslt = delete_failed_job(store, "id2").await;
+        let rslt = delete_failed_job(store.clone(), &Namespace::default(), &job_id(2, "id2")).await;
+        assert_eq!(
+            rslt,
+            Err(ResqueError::Conflict(format!(
+                "job {} has moved to {}",
+                job_id(2, "id2"),
+                job_id(1, "id2")
+            )))
+        );
+        assert_eq!(store.connection.lock().unwrap().received.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn delete_failed_job_reports_ambiguous_entry() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Bulk(vec![
+                    Value::Data(Vec::from("id2")),
+                    Value::Data(Vec::from("id2")),
+                ]),
+                Value::Nil,
+            ],
+        );
+        match delete_failed_job(store, &Namespace::default(), &job_id(5, "id2")).await {
+            Err(ResqueError::Conflict(msg)) => assert!(msg.contains("ambiguous")),
+            other => panic!("expected a conflict: {:?}", other),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn retry_failed_job_uses_original_queue() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Data(Vec::from(MAILER_JOB)),
+            ],
+        );
+        let rslt = retry_failed_job(
+            store.clone(),
+            &Namespace::default(),
+            &job_id(0, MAILER_JOB),
+            None,
+            RetryMode::Remove,
+        )
+        .await;
         assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(connection.received.len(), 2);
+        let args = command_args(&connection.received[1]);
+        assert_eq!(args[0], "EVALSHA");
+        assert_eq!(args[4], "resque:queue:mailers");
+        assert_eq!(args[5], "resque:queues");
+        assert_eq!(args[8], r#"{"class":"Job","args":["id1"]}"#);
+        assert_eq!(args[9], "mailers");
     }
 
     #[actix_rt::test]
-    async fn delete_failed_job_queue_empties() {
+    async fn retry_failed_job_can_keep_failure() {
         let store = RedisStore::new(
             Vec::new(),
             vec![
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Data(Vec::from(MAILER_JOB)),
+            ],
+        );
+        let rslt = retry_failed_job(
+            store.clone(),
+            &Namespace::default(),
+            &job_id(0, MAILER_JOB),
+            None,
+            RetryMode::Keep,
+        )
+        .await;
+        assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[1]);
+        assert_eq!(args.len(), 11);
+        let kept = Failure::parse(&args[10]).unwrap();
+        assert!(kept.retried_at.is_some());
+        assert_eq!(kept.queue.as_deref(), Some("mailers"));
+    }
+
+    #[actix_rt::test]
+    async fn retry_edited_job_records_both_payloads() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Data(Vec::from(MAILER_JOB)),
+            ],
+        );
+        let edited = serde_json::json!({"class": "Job", "args": ["id2"], "retry": true});
+        let rslt = retry_edited_job(
+            store.clone(),
+            &Namespace::default(),
+            &job_id(0, MAILER_JOB),
+            None,
+            edited,
+        )
+        .await;
+        assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[1]);
+        assert_eq!(args[8], r#"{"class":"Job","args":["id2"],"retry":true}"#);
+        let kept: serde_json::Value = serde_json::from_str(&args[10]).unwrap();
+        assert_eq!(kept["payload"]["args"][0], "id1");
+        assert_eq!(kept["retried_payload"]["args"][0], "id2");
+        assert!(kept["retried_at"].is_string());
+    }
+
+    #[actix_rt::test]
+    async fn retry_edited_job_rejects_non_resque_payloads() {
+        let store = RedisStore::new(Vec::new(), Vec::new());
+        let ns = Namespace::default();
+        let id = job_id(0, MAILER_JOB);
+        for payload in [
+            serde_json::json!(["Job"]),
+            serde_json::json!({"args": []}),
+            serde_json::json!({"class": "", "args": []}),
+            serde_json::json!({"class": "Job", "args": "id1"}),
+        ] {
+            let rslt = retry_edited_job(store.clone(), &ns, &id, None, payload).await;
+            assert!(matches!(rslt, Err(ResqueError::Invalid(_))));
+        }
+        assert!(store.connection.lock().unwrap().received.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn retry_failed_job_honors_override() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Data(Vec::from(MAILER_JOB)),
+            ],
+        );
+        let rslt = retry_failed_job(
+            store.clone(),
+            &Namespace::default(),
+            &job_id(0, MAILER_JOB),
+            Some("low"),
+            RetryMode::Remove,
+        )
+        .await;
+        assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[1]);
+        assert_eq!(args[4], "resque:queue:low");
+    }
+
+    #[actix_rt::test]
+    async fn concurrent_retries_requeue_once() {
+        let id = job_id(0, MAILER_JOB);
+        // Both callers read the entry before either script runs. The first script
+        // removes it so the second one finds the list changed and does nothing.
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Nil,
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Data(Vec::from(MAILER_JOB)),
+            ],
+        );
+        let ns = Namespace::default();
+        let (first, second) = futures_util::join!(
+            retry_failed_job(store.clone(), &ns, &id, None, RetryMode::Remove),
+            retry_failed_job(store.clone(), &ns, &id, None, RetryMode::Remove)
+        );
+        assert_eq!(first, Ok(()));
+        assert_eq!(second, Err(changed_job(&id)));
+        let connection = store.connection.lock().unwrap();
+        let commands: Vec<String> = connection
+            .received
+            .iter()
+            .map(|cmd| command_args(cmd)[0].clone())
+            .collect();
+        assert_eq!(commands, vec!["LINDEX", "LINDEX", "EVALSHA", "EVALSHA"]);
+    }
+
+    #[actix_rt::test]
+    async fn delete_during_retry_leaves_nothing_queued() {
+        let id = job_id(0, MAILER_JOB);
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Nil,
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Data(Vec::from(MAILER_JOB)),
+            ],
+        );
+        let ns = Namespace::default();
+        let (deleted, retried) = futures_util::join!(
+            delete_failed_job(store.clone(), &ns, &id),
+            retry_failed_job(store.clone(), &ns, &id, None, RetryMode::Remove)
+        );
+        assert_eq!(deleted, Ok(()));
+        assert_eq!(retried, Err(changed_job(&id)));
+        let connection = store.connection.lock().unwrap();
+        assert!(connection
+            .received
+            .iter()
+            .all(|cmd| command_args(cmd)[0] != "RPUSH"));
+    }
+
+    #[actix_rt::test]
+    async fn retry_failed_job_without_queue_keeps_failure() {
+        let raw = r#"{"payload":{"class":"Job","args":["id1"]}}"#;
+        let store = RedisStore::new(Vec::new(), vec![Value::Data(Vec::from(raw))]);
+        let rslt = retry_failed_job(
+            store.clone(),
+            &Namespace::default(),
+            &job_id(0, raw),
+            None,
+            RetryMode::Remove,
+        )
+        .await;
+        assert!(rslt.is_err());
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(connection.received.len(), 1);
+    }
+
+    pub(crate) fn data_list(entries: &[&str]) -> Value {
+        Value::Bulk(
+            entries
+                .iter()
+                .map(|entry| Value::Data(Vec::from(*entry)))
+                .collect(),
+        )
+    }
+
+    #[actix_rt::test]
+    async fn retry_all_skips_unparseable_jobs() {
+        let entries = [MAILER_JOB, "{not json", r#"{"payload":{"class":"Job"}}"#];
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Int(2),
                 Value::Int(1),
-                Value::Bulk(vec![Value::Data(Vec::from("id1"))]),
+                data_list(&entries),
                 Value::Int(3),
             ],
         );
-        if let Ok(()) = delete_failed_job(store, "id2").await {
-            panic!("should not have found a value")
-        }
+        let rslt = retry_all_jobs(store.clone(), &Namespace::default(), RetryMode::Remove)
+            .await
+            .unwrap();
+        assert_eq!(
+            rslt,
+            RetryAllSummary {
+                moved: 1,
+                skipped: 2,
+                left_behind: 0
+            }
+        );
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[2]);
+        assert_eq!(args[0], "EVALSHA");
+        assert_eq!(args[2], "3");
+        assert_eq!(args[5], "resque:queue:mailers");
+        assert_eq!(args[6], "0");
     }
+
     #[actix_rt::test]
-    async fn queue_stats_populated() {
+    async fn retry_all_keeping_failures_walks_the_whole_list() {
+        let first = vec![MAILER_JOB; 100];
+        let second = vec![MAILER_JOB; 20];
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Int(120),
+                Value::Int(20),
+                data_list(&second),
+                Value::Int(100),
+                data_list(&first),
+                Value::Int(120),
+            ],
+        );
+        let rslt = retry_all_jobs(store.clone(), &Namespace::default(), RetryMode::Keep)
+            .await
+            .unwrap();
+        assert_eq!(
+            rslt,
+            RetryAllSummary {
+                moved: 120,
+                skipped: 0,
+                left_behind: 0
+            }
+        );
+        let connection = store.connection.lock().unwrap();
+        let ranges: Vec<Vec<String>> = connection
+            .received
+            .iter()
+            .map(command_args)
+            .filter(|args| args[0] == "LRANGE")
+            .collect();
+        assert_eq!(ranges[1][2..], ["100".to_string(), "199".to_string()]);
+    }
+
+    #[actix_rt::test]
+    async fn retry_all_keeping_failures_skips_retried_ones() {
+        let retried = r#"{"payload":{"class":"Job","args":["id0"]},"queue":"mailers","retried_at":"2021/03/01 12:00:00 UTC"}"#;
         let store = RedisStore::new(
             Vec::new(),
+            vec![
+                Value::Int(2),
+                Value::Int(1),
+                data_list(&[retried, MAILER_JOB]),
+                Value::Int(2),
+            ],
+        );
+        let rslt = retry_all_jobs(store.clone(), &Namespace::default(), RetryMode::Keep)
+            .await
+            .unwrap();
+        assert_eq!(
+            rslt,
+            RetryAllSummary {
+                moved: 1,
+                skipped: 1,
+                left_behind: 0
+            }
+        );
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[2]);
+        assert_eq!(args[0], "EVALSHA");
+        assert!(!args.iter().any(|arg| arg.contains("id0")));
+        assert!(args.iter().any(|arg| arg.contains("id1")));
+    }
+
+    #[actix_rt::test]
+    async fn retry_all_pages_and_keeps_new_failures() {
+        let first = vec![MAILER_JOB; 100];
+        let second = vec![MAILER_JOB; 50];
+        // One job in the second batch changed underneath the script and two new failures
+        // arrived while the retry was running.
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Int(3),
+                Value::Int(49),
+                data_list(&second),
+                Value::Int(100),
+                data_list(&first),
+                Value::Int(150),
+            ],
+        );
+        let rslt = retry_all_jobs(store.clone(), &Namespace::default(), RetryMode::Remove)
+            .await
+            .unwrap();
+        assert_eq!(
+            rslt,
+            RetryAllSummary {
+                moved: 149,
+                skipped: 0,
+                left_behind: 3
+            }
+        );
+        let connection = store.connection.lock().unwrap();
+        let ranges: Vec<Vec<String>> = connection
+            .received
+            .iter()
+            .map(command_args)
+            .filter(|args| args[0] == "LRANGE")
+            .collect();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[1][2..], ["0".to_string(), "99".to_string()]);
+        assert!(connection
+            .received
+            .iter()
+            .all(|cmd| command_args(cmd)[0] != "DEL"));
+    }
+
+    #[actix_rt::test]
+    async fn queue_stats_populated() {
+        let store = RedisStore::new(Vec::new(), Vec::new()).pipelined(vec![
+            vec![Value::Int(0), Value::Nil, Value::Int(0), Value::Int(-2)],
             vec![
                 Value::Bulk(vec![Value::Data(Vec::from("default"))]),
                 Value::Int(123),
                 Value::Int(456),
+                data_list(&[]),
             ],
-        );
-        let rslt = queue_stats(store).await.unwrap();
+        ]);
+        let rslt = queue_stats(store, &Namespace::default()).await.unwrap();
         let expected = ResqueStats {
             available_queues: vec!["default".to_string()],
             failure_count: 123,
             success_count: 456,
+            queues: vec![QueueStats {
+                name: "default".to_string(),
+                length: 0,
+                oldest_job_age: None,
+                workers: 0,
+                unattended: false,
+                paused: false,
+                pause_expires_in: None,
+            }],
         };
         assert_eq!(rslt.available_queues, expected.available_queues);
         assert_eq!(rslt.failure_count, expected.failure_count);
         assert_eq!(rslt.success_count, expected.success_count);
+        assert_eq!(rslt.queues, expected.queues);
     }
 
     #[actix_rt::test]
     async fn queue_stats_no_counts() {
-        let store = RedisStore::new(
-            Vec::new(),
+        let store = RedisStore::new(Vec::new(), Vec::new()).pipelined(vec![
+            vec![Value::Int(0), Value::Nil, Value::Int(0), Value::Int(-2)],
             vec![
                 Value::Bulk(vec![Value::Data(Vec::from("default"))]),
                 Value::Nil,
                 Value::Nil,
+                data_list(&[]),
             ],
-        );
-        let rslt = queue_stats(store).await.unwrap();
+        ]);
+        let rslt = queue_stats(store, &Namespace::default()).await.unwrap();
         let expected = ResqueStats {
             available_queues: vec!["default".to_string()],
             failure_count: 0,
             success_count: 0,
+            queues: vec![QueueStats {
+                name: "default".to_string(),
+                length: 0,
+                oldest_job_age: None,
+                workers: 0,
+                unattended: false,
+                paused: false,
+                pause_expires_in: None,
+            }],
         };
         assert_eq!(rslt.available_queues, expected.available_queues);
         assert_eq!(rslt.failure_count, expected.failure_count);
         assert_eq!(rslt.success_count, expected.success_count);
+        assert_eq!(rslt.queues, expected.queues);
+    }
+
+    #[actix_rt::test]
+    async fn queue_stats_per_queue() {
+        let enqueued_at = (chrono::Utc::now() - chrono::Duration::seconds(120)).to_rfc3339();
+        let oldest = format!(
+            r#"{{"class":"ActiveJob::QueueAdapters::ResqueAdapter::JobWrapper","args":[{{"job_class":"Mailer","enqueued_at":"{}"}}]}}"#,
+            enqueued_at
+        );
+        let store = RedisStore::new(Vec::new(), Vec::new()).pipelined(vec![
+            vec![
+                Value::Int(3),
+                Value::Data(Vec::from(oldest)),
+                Value::Int(0),
+                Value::Int(-2),
+                Value::Int(0),
+                Value::Nil,
+                Value::Int(1),
+                Value::Int(-1),
+                Value::Int(7),
+                Value::Data(Vec::from(MAILER_JOB)),
+                Value::Int(1),
+                Value::Int(90),
+            ],
+            vec![
+                data_list(&["mail", "reports", "default"]),
+                Value::Nil,
+                Value::Nil,
+                data_list(&["web:1:default,mail", "web:2:mail"]),
+            ],
+        ]);
+        let rslt = queue_stats(store.clone(), &Namespace::default())
+            .await
+            .unwrap();
+        assert_eq!(rslt.available_queues, vec!["default", "mail", "reports"]);
+        let default = &rslt.queues[0];
+        assert_eq!((default.length, default.workers), (3, 1));
+        assert!((120..130).contains(&default.oldest_job_age.unwrap()));
+        assert!(!default.unattended);
+        assert!(!default.paused);
+        assert_eq!(rslt.queues[1].workers, 2);
+        assert_eq!(rslt.queues[1].oldest_job_age, None);
+        assert_eq!(
+            (rslt.queues[1].paused, rslt.queues[1].pause_expires_in),
+            (true, None)
+        );
+        let reports = &rslt.queues[2];
+        assert_eq!((reports.length, reports.workers), (7, 0));
+        assert!(reports.unattended);
+        assert_eq!(reports.pause_expires_in, Some(90));
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(connection.round_trips, 2);
+        assert_eq!(
+            command_args(&connection.received[5]),
+            vec!["LINDEX", "resque:queue:default", "0"]
+        );
+        assert_eq!(
+            command_args(&connection.received[6]),
+            vec!["EXISTS", "resque:pause:queue:default"]
+        );
     }
 }
@@ -0,0 +1,48 @@
+/// Prefix for every Redis key the app reads or writes. This should match the
+/// `Resque.redis.namespace` of the application being monitored, which defaults to `resque`.
+#[derive(Clone, Debug)]
+pub struct Namespace(String);
+
+impl Namespace {
+    pub fn new(prefix: &str) -> Self {
+        Namespace(prefix.trim_end_matches(':').to_string())
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.0
+    }
+
+    pub fn key(&self, name: &str) -> String {
+        if self.0.is_empty() {
+            return name.to_string();
+        }
+        format!("{}:{}", self.0, name)
+    }
+
+    pub fn queue(&self, name: &str) -> String {
+        self.key(&format!("queue:{}", name))
+    }
+}
+
+impl Default for Namespace {
+    fn default() -> Self {
+        Namespace::new("resque")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_nested_keys() {
+        let ns = Namespace::new("resque:production:");
+        assert_eq!(ns.key("failed"), "resque:production:failed");
+        assert_eq!(ns.queue("mailers"), "resque:production:queue:mailers");
+    }
+
+    #[test]
+    fn empty_namespace_leaves_keys_bare() {
+        assert_eq!(Namespace::new("").key("queues"), "queues");
+    }
+}
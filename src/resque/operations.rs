@@ -0,0 +1,459 @@
+use super::filter::FailedBatches;
+use super::{json_failed, Namespace, ResqueError, ResqueResult};
+use chrono::{DateTime, Duration, Utc};
+use redis::AsyncCommands;
+use serde_derive::{Deserialize, Serialize};
+
+/// Finished operations are kept for a day so their outcome can still be looked up.
+const FINISHED_TTL: usize = 86400;
+
+/// A running operation that hasn't saved its progress for this long was left behind by a
+/// server that went away. Exclusive operations hold their lock for the same time.
+const STALE_AFTER: usize = 300;
+
+/// Running records expire unless progress keeps being saved, so an operation whose server
+/// went away is shown as abandoned for a while and then cleaned up.
+const RUNNING_TTL: usize = STALE_AFTER * 6;
+
+const OPERATION_LOCK: &str = include_str!("scripts/operation_lock.lua");
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationState {
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+    Abandoned,
+}
+
+/// A bulk action running in the background. The record lives in Redis so any server
+/// sharing the database can report on it or cancel it. `updated_at` moves on with every
+/// batch, a running operation that stops updating is reported as abandoned. Only one
+/// exclusive operation of each kind runs at a time.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Operation {
+    pub id: u64,
+    pub kind: String,
+    #[serde(default)]
+    pub exclusive: bool,
+    pub state: OperationState,
+    pub total: u64,
+    pub processed: u64,
+    pub matched: u64,
+    pub affected: u64,
+    pub skipped: u64,
+    pub errors: Vec<String>,
+    pub started_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl Operation {
+    pub fn is_running(&self) -> bool {
+        self.state == OperationState::Running
+    }
+
+    /// Copies the progress of a walk over the failed list.
+    pub fn record(&mut self, batches: &FailedBatches) {
+        self.total = batches.total();
+        self.processed = batches.total() - batches.remaining();
+        self.matched = batches.outcome.matched;
+        self.affected = batches.outcome.affected;
+        self.skipped = batches.outcome.skipped;
+    }
+
+    // Marks a running operation abandoned once it has gone quiet.
+    fn settle(mut self, now: DateTime<Utc>) -> Self {
+        if self.is_running() && now - self.updated_at > Duration::seconds(STALE_AFTER as i64) {
+            self.state = OperationState::Abandoned;
+        }
+        self
+    }
+
+    pub fn finish(&mut self, result: Result<OperationState, String>) {
+        self.state = match result {
+            Ok(state) => state,
+            Err(err) => {
+                self.errors.push(err);
+                OperationState::Failed
+            }
+        };
+        self.finished_at = Some(Utc::now());
+    }
+}
+
+fn operation_key(ns: &Namespace, id: u64) -> String {
+    ns.key(&format!("web:operation:{}", id))
+}
+
+fn cancel_key(ns: &Namespace, id: u64) -> String {
+    ns.key(&format!("web:operation:{}:cancel", id))
+}
+
+fn lock_key(ns: &Namespace, kind: &str) -> String {
+    ns.key(&format!("web:operation_lock:{}", kind))
+}
+
+/// Records a new running operation. An exclusive operation first claims the lock for its
+/// kind and is refused while another one holds it.
+pub async fn start_operation(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    kind: &str,
+    exclusive: bool,
+) -> ResqueResult<Operation> {
+    let id: u64 = con.incr(ns.key("web:operation_id"), 1).await?;
+    if exclusive {
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(lock_key(ns, kind))
+            .arg(id)
+            .arg("NX")
+            .arg("EX")
+            .arg(STALE_AFTER)
+            .query_async(&mut con)
+            .await?;
+        if claimed.is_none() {
+            return Err(ResqueError::Conflict(format!(
+                "a {} operation is already running",
+                kind
+            )));
+        }
+    }
+    let now = Utc::now();
+    let operation = Operation {
+        id,
+        kind: kind.to_string(),
+        exclusive,
+        state: OperationState::Running,
+        total: 0,
+        processed: 0,
+        matched: 0,
+        affected: 0,
+        skipped: 0,
+        errors: Vec::new(),
+        started_at: now,
+        updated_at: now,
+        finished_at: None,
+    };
+    let raw = serde_json::to_string(&operation).map_err(json_failed)?;
+    redis::pipe()
+        .atomic()
+        .set_ex(operation_key(ns, id), raw, RUNNING_TTL)
+        .ignore()
+        .zadd(ns.key("web:operations"), id, now.timestamp())
+        .ignore()
+        .query_async::<_, ()>(&mut con)
+        .await?;
+    Ok(operation)
+}
+
+/// Writes an operation's progress back, extending its record and lock while it runs. Once
+/// it has finished the record is kept for a day and the lock is released.
+pub async fn save_operation(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    operation: &mut Operation,
+) -> redis::RedisResult<()> {
+    store_operation(&mut con, ns, operation).await
+}
+
+async fn store_operation(
+    con: &mut impl AsyncCommands,
+    ns: &Namespace,
+    operation: &mut Operation,
+) -> redis::RedisResult<()> {
+    operation.updated_at = Utc::now();
+    let key = operation_key(ns, operation.id);
+    let raw = serde_json::to_string(&operation).map_err(json_failed)?;
+    let ttl = if operation.is_running() {
+        RUNNING_TTL
+    } else {
+        FINISHED_TTL
+    };
+    con.set_ex::<_, _, ()>(key, raw, ttl).await?;
+    if operation.exclusive {
+        let script = redis::Script::new(OPERATION_LOCK);
+        let mut invocation = script.key(lock_key(ns, &operation.kind));
+        invocation.arg(operation.id);
+        if operation.is_running() {
+            invocation.arg(STALE_AFTER);
+        }
+        invocation.invoke_async::<_, ()>(con).await?;
+    }
+    Ok(())
+}
+
+pub async fn operation(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    id: u64,
+) -> ResqueResult<Operation> {
+    load_operation(&mut con, ns, id).await
+}
+
+async fn load_operation(
+    con: &mut impl AsyncCommands,
+    ns: &Namespace,
+    id: u64,
+) -> ResqueResult<Operation> {
+    let raw: Option<String> = con.get(operation_key(ns, id)).await?;
+    match raw {
+        Some(raw) => {
+            let operation: Operation = serde_json::from_str(&raw).map_err(json_failed)?;
+            Ok(operation.settle(Utc::now()))
+        }
+        None => Err(ResqueError::NotFound(format!("operation {} not found", id))),
+    }
+}
+
+/// Every operation that is running or finished within the last day, newest first.
+pub async fn operations(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+) -> redis::RedisResult<Vec<Operation>> {
+    let index = ns.key("web:operations");
+    let ids: Vec<u64> = con.zrevrange(&index, 0, -1).await?;
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let keys: Vec<String> = ids.iter().map(|id| operation_key(ns, *id)).collect();
+    let records: Vec<Option<String>> = redis::cmd("MGET").arg(keys).query_async(&mut con).await?;
+    let now = Utc::now();
+    let mut expired = Vec::new();
+    let mut found = Vec::new();
+    for (id, raw) in ids.into_iter().zip(records) {
+        match raw {
+            Some(raw) => {
+                let operation: Operation = serde_json::from_str(&raw).map_err(json_failed)?;
+                found.push(operation.settle(now));
+            }
+            None => expired.push(id),
+        }
+    }
+    if !expired.is_empty() {
+        con.zrem::<_, _, ()>(&index, expired).await?;
+    }
+    Ok(found)
+}
+
+/// Asks a running operation to stop before its next batch. Work already done stays done.
+/// An abandoned operation has nothing left to stop it, so it is finished as cancelled.
+pub async fn cancel_operation(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    id: u64,
+) -> ResqueResult<Operation> {
+    let mut operation = load_operation(&mut con, ns, id).await?;
+    let abandoned = operation.state == OperationState::Abandoned;
+    if operation.is_running() || abandoned {
+        con.set_ex::<_, _, ()>(cancel_key(ns, id), "true", FINISHED_TTL)
+            .await?;
+    }
+    if abandoned {
+        operation.finish(Ok(OperationState::Cancelled));
+        store_operation(&mut con, ns, &mut operation).await?;
+    }
+    Ok(operation)
+}
+
+pub async fn cancel_requested(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    id: u64,
+) -> redis::RedisResult<bool> {
+    con.exists(cancel_key(ns, id)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::command_args;
+    use super::super::tests::mock_redis::RedisStore;
+    use super::*;
+    use redis::Value;
+
+    fn running(id: u64) -> Operation {
+        let now = Utc::now();
+        Operation {
+            id,
+            kind: String::from("retry_all"),
+            exclusive: false,
+            state: OperationState::Running,
+            total: 20,
+            processed: 10,
+            matched: 10,
+            affected: 9,
+            skipped: 1,
+            errors: Vec::new(),
+            started_at: now,
+            updated_at: now,
+            finished_at: None,
+        }
+    }
+
+    fn stored(operation: &Operation) -> Value {
+        Value::Data(serde_json::to_string(operation).unwrap().into_bytes())
+    }
+
+    #[actix_rt::test]
+    async fn starts_and_indexes_operations() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Int(7)])
+            .pipelined(vec![vec![Value::Bulk(vec![Value::Okay, Value::Int(1)])]]);
+        let operation =
+            start_operation(store.clone(), &Namespace::default(), "clear_failed", false)
+                .await
+                .unwrap();
+        assert_eq!(operation.id, 7);
+        assert!(operation.is_running());
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[1]);
+        assert_eq!(args[..3], ["SETEX", "resque:web:operation:7", "1800"]);
+        let args = command_args(&connection.received[2]);
+        assert_eq!(args[0], "ZADD");
+        assert_eq!(args[1], "resque:web:operations");
+        assert_eq!(args[3], "7");
+    }
+
+    #[actix_rt::test]
+    async fn saving_progress_extends_running_operations() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Okay]);
+        let mut operation = running(4);
+        save_operation(store.clone(), &Namespace::default(), &mut operation)
+            .await
+            .unwrap();
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[0]);
+        assert_eq!(args[..3], ["SETEX", "resque:web:operation:4", "1800"]);
+    }
+
+    #[actix_rt::test]
+    async fn finished_operations_expire() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Okay]);
+        let mut operation = running(3);
+        operation.finish(Err(String::from("connection reset")));
+        save_operation(store.clone(), &Namespace::default(), &mut operation)
+            .await
+            .unwrap();
+        assert_eq!(operation.state, OperationState::Failed);
+        assert_eq!(operation.errors, vec!["connection reset"]);
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[0]);
+        assert_eq!(args[..2], ["SETEX", "resque:web:operation:3"]);
+        assert_eq!(args[2], "86400");
+    }
+
+    #[actix_rt::test]
+    async fn lists_operations_and_forgets_expired_ones() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Int(1),
+                Value::Bulk(vec![stored(&running(5)), Value::Nil]),
+                Value::Bulk(vec![
+                    Value::Data(Vec::from("5")),
+                    Value::Data(Vec::from("4")),
+                ]),
+            ],
+        );
+        let found = operations(store.clone(), &Namespace::default())
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, 5);
+        assert_eq!(found[0].affected, 9);
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(
+            command_args(&connection.received[1]),
+            vec!["MGET", "resque:web:operation:5", "resque:web:operation:4"]
+        );
+        assert_eq!(
+            command_args(&connection.received[2]),
+            vec!["ZREM", "resque:web:operations", "4"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn cancels_only_running_operations() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Okay, stored(&running(2))]);
+        let ns = Namespace::default();
+        cancel_operation(store.clone(), &ns, 2).await.unwrap();
+        assert_eq!(
+            command_args(&store.connection.lock().unwrap().received[1])[..2],
+            ["SETEX", "resque:web:operation:2:cancel"]
+        );
+
+        let mut finished = running(2);
+        finished.finish(Ok(OperationState::Completed));
+        let store = RedisStore::new(Vec::new(), vec![stored(&finished)]);
+        let operation = cancel_operation(store.clone(), &ns, 2).await.unwrap();
+        assert_eq!(operation.state, OperationState::Completed);
+        assert_eq!(store.connection.lock().unwrap().received.len(), 1);
+
+        let store = RedisStore::new(Vec::new(), vec![Value::Nil]);
+        let rslt = cancel_operation(store, &ns, 9).await;
+        assert!(matches!(rslt, Err(ResqueError::NotFound(_))));
+    }
+
+    #[actix_rt::test]
+    async fn exclusive_operations_claim_a_lock() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Nil, Value::Int(8)]);
+        let rslt = start_operation(
+            store.clone(),
+            &Namespace::default(),
+            "throttled_retry_all",
+            true,
+        )
+        .await;
+        assert!(matches!(rslt, Err(ResqueError::Conflict(_))));
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(connection.received.len(), 2);
+        assert_eq!(
+            command_args(&connection.received[1]),
+            vec![
+                "SET",
+                "resque:web:operation_lock:throttled_retry_all",
+                "8",
+                "NX",
+                "EX",
+                "300"
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn finishing_releases_the_lock() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Int(1), Value::Okay]);
+        let mut operation = running(4);
+        operation.kind = String::from("throttled_retry_all");
+        operation.exclusive = true;
+        operation.finish(Ok(OperationState::Completed));
+        save_operation(store.clone(), &Namespace::default(), &mut operation)
+            .await
+            .unwrap();
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[1]);
+        assert_eq!(args[0], "EVALSHA");
+        assert_eq!(
+            &args[2..],
+            &["1", "resque:web:operation_lock:throttled_retry_all", "4"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn quiet_operations_are_abandoned_and_can_be_cancelled() {
+        let mut quiet = running(6);
+        quiet.updated_at = Utc::now() - Duration::seconds(STALE_AFTER as i64 + 60);
+        let store = RedisStore::new(Vec::new(), vec![stored(&quiet)]);
+        let ns = Namespace::default();
+        let operation = operation(store.clone(), &ns, 6).await.unwrap();
+        assert_eq!(operation.state, OperationState::Abandoned);
+
+        let store = RedisStore::new(Vec::new(), vec![Value::Okay, Value::Okay, stored(&quiet)]);
+        let operation = cancel_operation(store.clone(), &ns, 6).await.unwrap();
+        assert_eq!(operation.state, OperationState::Cancelled);
+        assert!(operation.finished_at.is_some());
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[2]);
+        assert_eq!(args[..3], ["SETEX", "resque:web:operation:6", "86400"]);
+    }
+}
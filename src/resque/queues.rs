@@ -0,0 +1,439 @@
+use super::failure::JobPayload;
+use super::{
+    changed_job, digest, job_id, json_failed, locate_job, ListEntry, Namespace, ResqueError,
+    ResqueResult, REMOVE_JOB, RETRY_JOB,
+};
+use redis::AsyncCommands;
+use serde_derive::Serialize;
+
+pub type QueuedEntry = ListEntry<JobPayload>;
+
+const MOVE_FRONT: &str = include_str!("scripts/move_front.lua");
+const REMOVE_QUEUE: &str = include_str!("scripts/remove_queue.lua");
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EnqueuedJob {
+    pub id: String,
+    pub queue: String,
+    pub payload: String,
+}
+
+/// Pushes a new job onto `queue` the way `Resque.enqueue_to` does, registering the queue
+/// so workers and the UI can find it.
+pub async fn enqueue_job(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    queue: &str,
+    job: &JobPayload,
+) -> ResqueResult<EnqueuedJob> {
+    if queue.is_empty() {
+        return Err(ResqueError::Invalid(String::from("queue name is required")));
+    }
+    if job.class.is_empty() {
+        return Err(ResqueError::Invalid(String::from("job class is required")));
+    }
+    let payload = serde_json::to_string(job).map_err(json_failed)?;
+    let (length,): (isize,) = redis::pipe()
+        .atomic()
+        .sadd(ns.key("queues"), queue)
+        .ignore()
+        .rpush(ns.queue(queue), &payload)
+        .query_async(&mut con)
+        .await?;
+    Ok(EnqueuedJob {
+        id: job_id(length - 1, &payload),
+        queue: queue.to_string(),
+        payload,
+    })
+}
+
+/// Removes a single pending job. Jobs are addressed with the same ids as failed jobs so a
+/// job that moved since the page was loaded is reported instead of removing its neighbour.
+pub async fn delete_queued_job(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    queue: &str,
+    id: &str,
+) -> ResqueResult<()> {
+    let key = ns.queue(queue);
+    let (index, raw) = locate_job(&mut con, &key, id).await?;
+    let removed: Option<String> = redis::Script::new(REMOVE_JOB)
+        .key(&key)
+        .arg(index)
+        .arg(digest(&raw))
+        .invoke_async(&mut con)
+        .await?;
+    removed.map(|_| ()).ok_or_else(|| changed_job(id))
+}
+
+/// Moves a pending job onto the back of another queue, registering that queue.
+pub async fn move_queued_job(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    queue: &str,
+    id: &str,
+    target: &str,
+) -> ResqueResult<()> {
+    if target.is_empty() || target == queue {
+        return Err(ResqueError::Invalid(format!(
+            "can't move a job from {} to {:?}",
+            queue, target
+        )));
+    }
+    let key = ns.queue(queue);
+    let (index, raw) = locate_job(&mut con, &key, id).await?;
+    let removed: Option<String> = redis::Script::new(RETRY_JOB)
+        .key(&key)
+        .key(ns.queue(target))
+        .key(ns.key("queues"))
+        .arg(index)
+        .arg(digest(&raw))
+        .arg(&raw)
+        .arg(target)
+        .invoke_async(&mut con)
+        .await?;
+    removed.map(|_| ()).ok_or_else(|| changed_job(id))
+}
+
+/// Moves a pending job to the front of its queue so it's the next one a worker picks up.
+pub async fn prioritize_queued_job(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    queue: &str,
+    id: &str,
+) -> ResqueResult<()> {
+    let key = ns.queue(queue);
+    let (index, raw) = locate_job(&mut con, &key, id).await?;
+    let moved: Option<String> = redis::Script::new(MOVE_FRONT)
+        .key(&key)
+        .arg(index)
+        .arg(digest(&raw))
+        .invoke_async(&mut con)
+        .await?;
+    moved.map(|_| ()).ok_or_else(|| changed_job(id))
+}
+
+/// Deletes a queue's jobs and unregisters its name, like `Resque.remove_queue`. Workers
+/// started with the queue by name would keep polling it, so unless `force` is set the
+/// queue is left alone while any are registered. Returns how many jobs were deleted.
+pub async fn remove_queue(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    queue: &str,
+    force: bool,
+) -> ResqueResult<u64> {
+    let script = redis::Script::new(REMOVE_QUEUE);
+    let mut invocation = script.key(ns.key("workers"));
+    invocation
+        .key(ns.queue(queue))
+        .key(ns.key("queues"))
+        .arg(queue);
+    if force {
+        invocation.arg("true");
+    }
+    // The check and the removal run together so a worker can't register in between.
+    let (deleted, listening): (u64, Vec<String>) = invocation.invoke_async(&mut con).await?;
+    if !listening.is_empty() {
+        return Err(ResqueError::Conflict(format!(
+            "workers are listening on {}: {}",
+            queue,
+            listening.join(", ")
+        )));
+    }
+    Ok(deleted)
+}
+
+// The key resque-pause sets to stop workers from taking jobs off a queue.
+pub(super) fn pause_key(ns: &Namespace, queue: &str) -> String {
+    ns.key(&format!("pause:queue:{}", queue))
+}
+
+/// Pauses a queue the way resque-pause does, optionally resuming it by itself after
+/// `expires_in` seconds.
+pub async fn pause_queue(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    queue: &str,
+    expires_in: Option<usize>,
+) -> ResqueResult<()> {
+    if queue.is_empty() {
+        return Err(ResqueError::Invalid(String::from("queue name is required")));
+    }
+    let key = pause_key(ns, queue);
+    match expires_in {
+        Some(0) => Err(ResqueError::Invalid(String::from(
+            "expiry must be at least a second",
+        ))),
+        Some(seconds) => Ok(con.set_ex::<_, _, ()>(key, "true", seconds).await?),
+        None => Ok(con.set::<_, _, ()>(key, "true").await?),
+    }
+}
+
+/// Resumes a paused queue, returning whether it was paused.
+pub async fn resume_queue(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    queue: &str,
+) -> redis::RedisResult<bool> {
+    con.del(pause_key(ns, queue)).await
+}
+
+/// Queue names registered in the `queues` set whose lists are empty or missing.
+pub async fn orphaned_queues(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+) -> redis::RedisResult<Vec<String>> {
+    let mut queues: Vec<String> = con.smembers(ns.key("queues")).await?;
+    if queues.is_empty() {
+        return Ok(queues);
+    }
+    queues.sort();
+    let mut pipe = redis::pipe();
+    for queue in queues.iter() {
+        pipe.llen(ns.queue(queue));
+    }
+    let lengths: Vec<u64> = pipe.query_async(&mut con).await?;
+    Ok(queues
+        .into_iter()
+        .zip(lengths)
+        .filter(|(_, length)| *length == 0)
+        .map(|(queue, _)| queue)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::mock_redis::RedisStore;
+    use super::super::tests::{command_args, data_list};
+    use super::*;
+    use redis::Value;
+
+    fn job(class: &str) -> JobPayload {
+        serde_json::from_str(&format!(r#"{{"class":"{}","args":[1,"two"]}}"#, class)).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn enqueue_registers_queue_and_pushes() {
+        let store = RedisStore::new(Vec::new(), Vec::new())
+            .pipelined(vec![vec![Value::Bulk(vec![Value::Int(1), Value::Int(3)])]]);
+        let enqueued = enqueue_job(
+            store.clone(),
+            &Namespace::new("jobs"),
+            "mail",
+            &job("Mailer"),
+        )
+        .await
+        .unwrap();
+        let payload = r#"{"class":"Mailer","args":[1,"two"]}"#;
+        assert_eq!(enqueued.payload, payload);
+        assert_eq!(enqueued.id, job_id(2, payload));
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(
+            command_args(&connection.received[0]),
+            vec!["SADD", "jobs:queues", "mail"]
+        );
+        assert_eq!(
+            command_args(&connection.received[1]),
+            vec!["RPUSH", "jobs:queue:mail", payload]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn enqueue_requires_class_and_queue() {
+        let store = RedisStore::new(Vec::new(), Vec::new());
+        let ns = Namespace::default();
+        assert!(matches!(
+            enqueue_job(store.clone(), &ns, "mail", &job("")).await,
+            Err(ResqueError::Invalid(_))
+        ));
+        assert!(matches!(
+            enqueue_job(store.clone(), &ns, "", &job("Mailer")).await,
+            Err(ResqueError::Invalid(_))
+        ));
+        assert!(store.connection.lock().unwrap().received.is_empty());
+    }
+
+    const QUEUED: &str = r#"{"class":"Mailer","args":[1]}"#;
+
+    fn found_job() -> RedisStore {
+        RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Data(Vec::from(QUEUED)),
+                Value::Data(Vec::from(QUEUED)),
+            ],
+        )
+    }
+
+    #[actix_rt::test]
+    async fn delete_queued_job_removes_matching_entry() {
+        let store = found_job();
+        let rslt = delete_queued_job(
+            store.clone(),
+            &Namespace::default(),
+            "mail",
+            &job_id(4, QUEUED),
+        )
+        .await;
+        assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(
+            command_args(&connection.received[0]),
+            vec!["LINDEX", "resque:queue:mail", "4"]
+        );
+        let args = command_args(&connection.received[1]);
+        assert_eq!(args[3], "resque:queue:mail");
+        assert_eq!(args[4], "4");
+        assert_eq!(args[5], digest(QUEUED));
+    }
+
+    #[actix_rt::test]
+    async fn move_queued_job_pushes_unchanged_payload() {
+        let store = found_job();
+        let rslt = move_queued_job(
+            store.clone(),
+            &Namespace::default(),
+            "mail",
+            &job_id(0, QUEUED),
+            "low",
+        )
+        .await;
+        assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[1]);
+        assert_eq!(
+            &args[3..],
+            &[
+                "resque:queue:mail",
+                "resque:queue:low",
+                "resque:queues",
+                "0",
+                &digest(QUEUED),
+                QUEUED,
+                "low"
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn move_queued_job_to_same_queue_is_rejected() {
+        let store = found_job();
+        let rslt = move_queued_job(
+            store.clone(),
+            &Namespace::default(),
+            "mail",
+            &job_id(0, QUEUED),
+            "mail",
+        )
+        .await;
+        assert!(matches!(rslt, Err(ResqueError::Invalid(_))));
+        assert!(store.connection.lock().unwrap().received.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn prioritize_reports_moved_job() {
+        let other = r#"{"class":"Other","args":[]}"#;
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![data_list(&[other, QUEUED]), Value::Data(Vec::from(other))],
+        );
+        let rslt =
+            prioritize_queued_job(store, &Namespace::default(), "mail", &job_id(0, QUEUED)).await;
+        assert_eq!(
+            rslt,
+            Err(ResqueError::Conflict(format!(
+                "job {} has moved to {}",
+                job_id(0, QUEUED),
+                job_id(1, QUEUED)
+            )))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn remove_queue_refuses_while_workers_listen() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![Value::Bulk(vec![
+                Value::Int(0),
+                data_list(&["web:2:mail,low"]),
+            ])],
+        );
+        let rslt = remove_queue(store.clone(), &Namespace::default(), "mail", false).await;
+        assert_eq!(
+            rslt,
+            Err(ResqueError::Conflict(String::from(
+                "workers are listening on mail: web:2:mail,low"
+            )))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn remove_queue_deletes_and_unregisters() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![Value::Bulk(vec![Value::Int(4), data_list(&[])])],
+        );
+        let rslt = remove_queue(store.clone(), &Namespace::default(), "mail", false).await;
+        assert_eq!(rslt, Ok(4));
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[0]);
+        assert_eq!(args[0], "EVALSHA");
+        assert_eq!(
+            args[2..],
+            [
+                "3",
+                "resque:workers",
+                "resque:queue:mail",
+                "resque:queues",
+                "mail"
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn forced_remove_skips_worker_check() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![Value::Bulk(vec![Value::Int(0), data_list(&[])])],
+        );
+        let rslt = remove_queue(store.clone(), &Namespace::default(), "mail", true).await;
+        assert_eq!(rslt, Ok(0));
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(command_args(&connection.received[0])[7], "true");
+    }
+
+    #[actix_rt::test]
+    async fn finds_orphaned_queues() {
+        let store = RedisStore::new(Vec::new(), vec![data_list(&["mail", "low", "high"])])
+            .pipelined(vec![vec![Value::Int(0), Value::Int(0), Value::Int(2)]]);
+        let rslt = orphaned_queues(store, &Namespace::default()).await.unwrap();
+        assert_eq!(rslt, vec!["high", "low"]);
+    }
+
+    #[actix_rt::test]
+    async fn pauses_like_resque_pause() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Okay, Value::Okay]);
+        let ns = Namespace::default();
+        pause_queue(store.clone(), &ns, "mail", None).await.unwrap();
+        pause_queue(store.clone(), &ns, "mail", Some(60))
+            .await
+            .unwrap();
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(
+            command_args(&connection.received[0]),
+            vec!["SET", "resque:pause:queue:mail", "true"]
+        );
+        assert_eq!(
+            command_args(&connection.received[1]),
+            vec!["SETEX", "resque:pause:queue:mail", "60", "true"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn resume_reports_whether_queue_was_paused() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Int(0), Value::Int(1)]);
+        let ns = Namespace::default();
+        assert_eq!(resume_queue(store.clone(), &ns, "mail").await, Ok(true));
+        assert_eq!(resume_queue(store.clone(), &ns, "mail").await, Ok(false));
+    }
+}
@@ -0,0 +1,395 @@
+use super::cron::{parse_interval, CronSchedule};
+use super::failure::JobPayload;
+use super::time::parse_time;
+use super::{json_failed, Namespace, ResqueError, ResqueResult};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// A dynamic schedule as resque-scheduler stores it in the `schedules` hash. `cron` and
+/// `every` may be a bare expression or an `[expression, options]` pair.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduleConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cron: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub every: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Recurrence {
+    Cron {
+        expression: String,
+        timezone: Option<String>,
+    },
+    Every {
+        expression: String,
+        seconds: i64,
+    },
+}
+
+#[derive(Serialize)]
+pub struct RecurringSchedule {
+    name: String,
+    config: Option<ScheduleConfig>,
+    recurrence: Option<Recurrence>,
+    next_run: Option<DateTime<Utc>>,
+    last_enqueued_at: Option<String>,
+    pending_reload: bool,
+    parse_error: Option<String>,
+}
+
+fn expression(val: &serde_json::Value) -> Option<&str> {
+    match val {
+        serde_json::Value::String(expression) => Some(expression),
+        serde_json::Value::Array(parts) => parts.first().and_then(|part| part.as_str()),
+        _ => None,
+    }
+}
+
+impl ScheduleConfig {
+    fn recurrence(&self) -> Result<(Recurrence, Option<CronSchedule>), String> {
+        match (self.cron.as_ref(), self.every.as_ref()) {
+            (Some(cron), None) => {
+                let line = expression(cron).ok_or("cron must be a string")?;
+                let schedule = CronSchedule::parse(line)?;
+                let recurrence = Recurrence::Cron {
+                    expression: line.to_string(),
+                    timezone: schedule.timezone.clone(),
+                };
+                Ok((recurrence, Some(schedule)))
+            }
+            (None, Some(every)) => {
+                let interval = expression(every).ok_or("every must be a string")?;
+                let recurrence = Recurrence::Every {
+                    expression: interval.to_string(),
+                    seconds: parse_interval(interval)?.num_seconds(),
+                };
+                Ok((recurrence, None))
+            }
+            (Some(_), Some(_)) => Err(String::from("schedule has both cron and every")),
+            (None, None) => Err(String::from("schedule needs either cron or every")),
+        }
+    }
+
+    // resque-scheduler falls back to the schedule name when no class is given.
+    fn payload(&self, name: &str) -> JobPayload {
+        let args = match self.args.clone() {
+            None | Some(serde_json::Value::Null) => Vec::new(),
+            Some(serde_json::Value::Array(args)) => args,
+            Some(other) => vec![other],
+        };
+        JobPayload {
+            class: self.class.clone().unwrap_or_else(|| name.to_string()),
+            args,
+            extra: serde_json::Map::new(),
+        }
+    }
+}
+
+impl RecurringSchedule {
+    fn new(
+        name: String,
+        raw: &str,
+        last_enqueued_at: Option<String>,
+        pending_reload: bool,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let parsed = serde_json::from_str::<ScheduleConfig>(raw)
+            .map_err(|err| err.to_string())
+            .map(|config| {
+                let recurrence = config.recurrence();
+                (config, recurrence)
+            });
+        let (config, recurrence, parse_error) = match parsed {
+            Ok((config, Ok(recurrence))) => (Some(config), Some(recurrence), None),
+            Ok((config, Err(err))) => (Some(config), None, Some(err)),
+            Err(err) => (None, None, Some(err)),
+        };
+        let next_run = match recurrence {
+            Some((_, Some(ref cron))) => cron.next_after(now),
+            Some((Recurrence::Every { seconds, .. }, None)) => last_enqueued_at
+                .as_deref()
+                .and_then(parse_time)
+                .and_then(|last| last.checked_add_signed(chrono::Duration::try_seconds(seconds)?)),
+            _ => None,
+        };
+        RecurringSchedule {
+            name,
+            config,
+            recurrence: recurrence.map(|(recurrence, _)| recurrence),
+            next_run,
+            last_enqueued_at,
+            pending_reload,
+            parse_error,
+        }
+    }
+}
+
+/// Lists every dynamic schedule along with when it will next run. Schedules that changed
+/// since resque-scheduler last loaded them are flagged as pending a reload.
+pub async fn recurring_schedules(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+) -> redis::RedisResult<Vec<RecurringSchedule>> {
+    let (schedules, changed, mut last_enqueued): (
+        HashMap<String, String>,
+        HashSet<String>,
+        HashMap<String, String>,
+    ) = redis::pipe()
+        .hgetall(ns.key("schedules"))
+        .smembers(ns.key("schedules_changed"))
+        .hgetall(ns.key("delayed:last_enqueued_at"))
+        .query_async(&mut con)
+        .await?;
+    let now = Utc::now();
+    let mut results: Vec<RecurringSchedule> = schedules
+        .into_iter()
+        .map(|(name, raw)| {
+            let pending_reload = changed.contains(&name);
+            let last = last_enqueued.remove(&name);
+            RecurringSchedule::new(name, &raw, last, pending_reload, now)
+        })
+        .collect();
+    results.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(results)
+}
+
+/// Adds or replaces a dynamic schedule and flags it so resque-scheduler reloads it.
+pub async fn set_schedule(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    name: &str,
+    mut config: ScheduleConfig,
+) -> ResqueResult<()> {
+    config.recurrence().map_err(ResqueError::Invalid)?;
+    let persist = config.extra.remove("persist") == Some(serde_json::Value::Bool(true));
+    let encoded = serde_json::to_string(&config).map_err(json_failed)?;
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .hset(ns.key("schedules"), name, &encoded)
+        .ignore();
+    if persist {
+        pipe.hset(ns.key("persistent_schedules"), name, &encoded)
+            .ignore();
+    }
+    pipe.sadd(ns.key("schedules_changed"), name)
+        .ignore()
+        .query_async::<_, ()>(&mut con)
+        .await?;
+    Ok(())
+}
+
+pub async fn remove_schedule(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    name: &str,
+) -> ResqueResult<()> {
+    let (removed,): (u64,) = redis::pipe()
+        .atomic()
+        .hdel(ns.key("schedules"), name)
+        .hdel(ns.key("persistent_schedules"), name)
+        .ignore()
+        .sadd(ns.key("schedules_changed"), name)
+        .ignore()
+        .query_async(&mut con)
+        .await?;
+    if removed == 0 {
+        return Err(ResqueError::NotFound(format!(
+            "schedule {} not found",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Enqueues the job for a schedule right away, outside of its normal recurrence.
+pub async fn enqueue_schedule(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    name: &str,
+) -> ResqueResult<()> {
+    let raw: Option<String> = con.hget(ns.key("schedules"), name).await?;
+    let raw = raw.ok_or_else(|| ResqueError::NotFound(format!("schedule {} not found", name)))?;
+    let config: ScheduleConfig = serde_json::from_str(&raw).map_err(json_failed)?;
+    let queue = match config.queue {
+        Some(ref queue) if !queue.is_empty() => queue.clone(),
+        _ => {
+            return Err(ResqueError::Invalid(format!(
+                "schedule {} has no queue",
+                name
+            )))
+        }
+    };
+    let payload = serde_json::to_string(&config.payload(name)).map_err(json_failed)?;
+    redis::pipe()
+        .rpush(ns.queue(&queue), payload)
+        .ignore()
+        .sadd(ns.key("queues"), &queue)
+        .ignore()
+        .query_async::<_, ()>(&mut con)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::command_args;
+    use super::super::tests::mock_redis::RedisStore;
+    use super::*;
+    use chrono::TimeZone;
+    use redis::Value;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2021, 3, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn cron_schedule_reports_next_run() {
+        let schedule = RecurringSchedule::new(
+            String::from("nightly"),
+            r#"{"cron":"0 2 * * *","class":"Nightly","queue":"low"}"#,
+            None,
+            false,
+            now(),
+        );
+        assert_eq!(
+            schedule.next_run,
+            Some(Utc.with_ymd_and_hms(2021, 3, 2, 2, 0, 0).unwrap())
+        );
+        assert_eq!(
+            schedule.recurrence,
+            Some(Recurrence::Cron {
+                expression: String::from("0 2 * * *"),
+                timezone: None
+            })
+        );
+    }
+
+    #[test]
+    fn every_schedule_counts_from_last_enqueue() {
+        let schedule = RecurringSchedule::new(
+            String::from("poll"),
+            r#"{"every":["30m",{"first_in":"1m"}],"queue":"low"}"#,
+            Some(String::from("2021-03-01 11:45:00 +0000")),
+            true,
+            now(),
+        );
+        assert_eq!(
+            schedule.next_run,
+            Some(Utc.with_ymd_and_hms(2021, 3, 1, 12, 15, 0).unwrap())
+        );
+        assert!(schedule.pending_reload);
+    }
+
+    #[test]
+    fn out_of_range_every_schedule_is_reported() {
+        for every in ["1e300", "-5", "inf"] {
+            let schedule = RecurringSchedule::new(
+                String::from("poll"),
+                &format!(r#"{{"every":"{}","queue":"low"}}"#, every),
+                Some(String::from("2021-03-01 11:45:00 +0000")),
+                false,
+                now(),
+            );
+            assert_eq!(schedule.next_run, None);
+            assert!(schedule.parse_error.is_some());
+        }
+    }
+
+    #[test]
+    fn invalid_schedule_keeps_config() {
+        let schedule = RecurringSchedule::new(
+            String::from("bad"),
+            r#"{"cron":"nope"}"#,
+            None,
+            false,
+            now(),
+        );
+        assert!(schedule.config.is_some());
+        assert!(schedule.recurrence.is_none());
+        assert!(schedule.parse_error.is_some());
+    }
+
+    #[test]
+    fn payload_defaults_class_to_name() {
+        let config: ScheduleConfig =
+            serde_json::from_str(r#"{"every":"1h","args":{"full":true}}"#).unwrap();
+        let payload = config.payload("CleanupJob");
+        assert_eq!(payload.class, "CleanupJob");
+        assert_eq!(payload.args, vec![serde_json::json!({"full": true})]);
+    }
+
+    #[actix_rt::test]
+    async fn set_schedule_rejects_bad_every() {
+        let store = RedisStore::new(Vec::new(), Vec::new());
+        for every in ["-5", "NaN", "1e300"] {
+            let config: ScheduleConfig =
+                serde_json::from_str(&format!(r#"{{"every":"{}"}}"#, every)).unwrap();
+            let rslt = set_schedule(store.clone(), &Namespace::default(), "bad", config).await;
+            assert!(matches!(rslt, Err(ResqueError::Invalid(_))));
+        }
+        assert!(store.connection.lock().unwrap().received.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn set_schedule_rejects_bad_cron() {
+        let store = RedisStore::new(Vec::new(), Vec::new());
+        let config: ScheduleConfig = serde_json::from_str(r#"{"cron":"* *"}"#).unwrap();
+        let rslt = set_schedule(store.clone(), &Namespace::default(), "bad", config).await;
+        assert!(matches!(rslt, Err(ResqueError::Invalid(_))));
+        assert!(store.connection.lock().unwrap().received.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn set_schedule_persists_when_asked() {
+        let store = RedisStore::new(Vec::new(), vec![Value::Bulk(Vec::new())]);
+        let config: ScheduleConfig =
+            serde_json::from_str(r#"{"cron":"0 * * * *","queue":"low","persist":true}"#).unwrap();
+        let rslt = set_schedule(store.clone(), &Namespace::default(), "hourly", config).await;
+        assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        let commands: Vec<Vec<String>> = connection.received.iter().map(command_args).collect();
+        assert_eq!(commands[0][1], "resque:schedules");
+        assert_eq!(commands[0][3], r#"{"cron":"0 * * * *","queue":"low"}"#);
+        assert_eq!(commands[1][1], "resque:persistent_schedules");
+        assert_eq!(
+            commands[2],
+            vec!["SADD", "resque:schedules_changed", "hourly"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn enqueue_schedule_pushes_payload() {
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![Value::Data(Vec::from(
+                r#"{"cron":"0 * * * *","class":"Hourly","queue":"low","args":[1]}"#,
+            ))],
+        );
+        let rslt = enqueue_schedule(store.clone(), &Namespace::default(), "hourly").await;
+        assert_eq!(rslt, Ok(()));
+        let connection = store.connection.lock().unwrap();
+        let args = command_args(&connection.received[1]);
+        assert_eq!(
+            args,
+            vec![
+                "RPUSH",
+                "resque:queue:low",
+                r#"{"class":"Hourly","args":[1]}"#
+            ]
+        );
+    }
+}
@@ -0,0 +1,10 @@
+-- Removes every job scheduled at the timestamp ARGV[1]. KEYS[1] is the delayed list,
+-- KEYS[2] the schedule and ARGV[2] the key prefix of the per-job timestamps sets.
+-- Returns the number of jobs removed.
+local entries = redis.call('LRANGE', KEYS[1], 0, -1)
+for _, entry in ipairs(entries) do
+  redis.call('SREM', ARGV[2] .. entry, 'delayed:' .. ARGV[1])
+end
+redis.call('DEL', KEYS[1])
+redis.call('ZREM', KEYS[2], ARGV[1])
+return #entries
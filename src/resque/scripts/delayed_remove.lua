@@ -0,0 +1,32 @@
+-- Removes the entry at ARGV[1] of the delayed list KEYS[1] if it still hashes to ARGV[2],
+-- keeping resque-scheduler's bookkeeping in step: the entry's timestamps set KEYS[2] and,
+-- once the list is empty, the timestamp ARGV[3] in the schedule KEYS[3]. When KEYS[4] and
+-- KEYS[5] are given the payload ARGV[4] is pushed onto the queue KEYS[4] and the queue
+-- name ARGV[5] is registered in the set KEYS[5]. Returns the removed entry or nil.
+local entry = redis.call('LINDEX', KEYS[1], ARGV[1])
+if not entry or redis.sha1hex(entry) ~= ARGV[2] then
+  return false
+end
+redis.call('LSET', KEYS[1], ARGV[1], '__resque_web_delete__')
+redis.call('LREM', KEYS[1], 1, '__resque_web_delete__')
+
+local remaining = redis.call('LRANGE', KEYS[1], 0, -1)
+local duplicated = false
+for _, item in ipairs(remaining) do
+  if item == entry then
+    duplicated = true
+    break
+  end
+end
+if not duplicated then
+  redis.call('SREM', KEYS[2], 'delayed:' .. ARGV[3])
+end
+if #remaining == 0 then
+  redis.call('ZREM', KEYS[3], ARGV[3])
+end
+
+if #KEYS == 5 then
+  redis.call('RPUSH', KEYS[4], ARGV[4])
+  redis.call('SADD', KEYS[5], ARGV[5])
+end
+return entry
@@ -0,0 +1,13 @@
+-- Deletes a batch of entries from the list KEYS[1]. ARGV holds an index and digest for
+-- each entry. Entries that changed since they were read are left in place. Returns the
+-- number of entries deleted.
+local deleted = 0
+for n = 1, #ARGV, 2 do
+  local entry = redis.call('LINDEX', KEYS[1], ARGV[n])
+  if entry and redis.sha1hex(entry) == ARGV[n + 1] then
+    redis.call('LSET', KEYS[1], ARGV[n], '__resque_web_delete__')
+    deleted = deleted + 1
+  end
+end
+redis.call('LREM', KEYS[1], 0, '__resque_web_delete__')
+return deleted
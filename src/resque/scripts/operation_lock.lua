@@ -0,0 +1,13 @@
+-- Refreshes or releases the lock held by an exclusive operation. KEYS[1] is the lock and
+-- ARGV[1] the operation id. With a ttl in ARGV[2] the lock is extended, without one it is
+-- released. A lock another operation has taken over is left alone. Returns 1 when the
+-- lock belonged to the operation.
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+  return 0
+end
+if ARGV[2] then
+  redis.call('EXPIRE', KEYS[1], ARGV[2])
+else
+  redis.call('DEL', KEYS[1])
+end
+return 1
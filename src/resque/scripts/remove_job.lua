@@ -0,0 +1,9 @@
+-- Removes the entry at ARGV[1] of the list KEYS[1] as long as it still hashes to ARGV[2].
+-- Returns the removed entry, or nil if the list changed since the caller read it.
+local entry = redis.call('LINDEX', KEYS[1], ARGV[1])
+if not entry or redis.sha1hex(entry) ~= ARGV[2] then
+  return false
+end
+redis.call('LSET', KEYS[1], ARGV[1], '__resque_web_delete__')
+redis.call('LREM', KEYS[1], 1, '__resque_web_delete__')
+return entry
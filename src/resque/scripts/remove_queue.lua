@@ -0,0 +1,25 @@
+-- Deletes the queue KEYS[2] and unregisters its name ARGV[1] from the set KEYS[3], like
+-- Resque.remove_queue. Unless ARGV[2] is given nothing is changed while a worker in the
+-- set KEYS[1] was started with the queue by name. Wildcard workers find their queues
+-- through the set, so they stop polling once it's unregistered. Returns the number of
+-- jobs deleted and the ids of the workers that kept the queue in place.
+if not ARGV[2] then
+  local listening = {}
+  for _, worker in ipairs(redis.call('SMEMBERS', KEYS[1])) do
+    -- Worker ids are hostname:pid:queue,queue.
+    local queues = string.match(worker, '^[^:]*:[^:]*:(.*)$') or ''
+    for name in string.gmatch(queues, '[^,]+') do
+      if name == ARGV[1] then
+        table.insert(listening, worker)
+        break
+      end
+    end
+  end
+  if #listening > 0 then
+    return {0, listening}
+  end
+end
+local deleted = redis.call('LLEN', KEYS[2])
+redis.call('DEL', KEYS[2])
+redis.call('SREM', KEYS[3], ARGV[1])
+return {deleted, {}}
@@ -0,0 +1,22 @@
+-- Requeues a batch of failed jobs. KEYS[1] is the failed list, KEYS[2] the set of queue
+-- names and KEYS[2 + n] the queue for the nth job. ARGV holds an index, digest, payload,
+-- queue name and replacement for each job. Entries with an empty replacement are removed
+-- from the failed list, the rest are overwritten with it. Entries that changed since they
+-- were read are left in place. Returns the number of jobs requeued.
+local moved = 0
+for n = 1, #KEYS - 2 do
+  local base = (n - 1) * 5
+  local entry = redis.call('LINDEX', KEYS[1], ARGV[base + 1])
+  if entry and redis.sha1hex(entry) == ARGV[base + 2] then
+    if ARGV[base + 5] == '' then
+      redis.call('LSET', KEYS[1], ARGV[base + 1], '__resque_web_delete__')
+    else
+      redis.call('LSET', KEYS[1], ARGV[base + 1], ARGV[base + 5])
+    end
+    redis.call('RPUSH', KEYS[2 + n], ARGV[base + 3])
+    redis.call('SADD', KEYS[2], ARGV[base + 4])
+    moved = moved + 1
+  end
+end
+redis.call('LREM', KEYS[1], 0, '__resque_web_delete__')
+return moved
@@ -0,0 +1,17 @@
+-- Moves the entry at ARGV[1] of the list KEYS[1] onto the queue KEYS[2] as the payload
+-- ARGV[3], registering the queue name ARGV[4] in the set KEYS[3]. When ARGV[5] is given
+-- the entry is replaced with it instead of being removed. Nothing is changed unless the
+-- entry still hashes to ARGV[2]. Returns the original entry or nil.
+local entry = redis.call('LINDEX', KEYS[1], ARGV[1])
+if not entry or redis.sha1hex(entry) ~= ARGV[2] then
+  return false
+end
+if ARGV[5] then
+  redis.call('LSET', KEYS[1], ARGV[1], ARGV[5])
+else
+  redis.call('LSET', KEYS[1], ARGV[1], '__resque_web_delete__')
+  redis.call('LREM', KEYS[1], 1, '__resque_web_delete__')
+end
+redis.call('RPUSH', KEYS[2], ARGV[3])
+redis.call('SADD', KEYS[3], ARGV[4])
+return entry
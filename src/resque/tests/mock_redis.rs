@@ -7,6 +7,8 @@ use std::sync::Mutex;
 pub struct MockConnection {
     pub received: Vec<Cmd>,
     pub to_send: Vec<Value>,
+    pub pipelined: Vec<Vec<Value>>,
+    pub round_trips: usize,
 }
 #[derive(Clone)]
 pub struct RedisStore {
@@ -20,9 +22,21 @@ impl RedisStore {
     // sent back to the app.
     pub fn new(received: Vec<Cmd>, to_send: Vec<Value>) -> Self {
         RedisStore {
-            connection: Arc::new(Mutex::new(MockConnection { received, to_send })),
+            connection: Arc::new(Mutex::new(MockConnection {
+                received,
+                to_send,
+                pipelined: Vec::new(),
+                round_trips: 0,
+            })),
         }
     }
+
+    // pipelined holds the responses for individual pipelines, pulled in reverse order like
+    // to_send. Without any, every pipeline is answered with the whole of to_send.
+    pub fn pipelined(self, pipelined: Vec<Vec<Value>>) -> Self {
+        self.connection.lock().unwrap().pipelined = pipelined;
+        self
+    }
 }
 
 impl ConnectionLike for RedisStore {
@@ -37,15 +51,23 @@ impl ConnectionLike for RedisStore {
     ) -> BoxFuture<'a, RedisResult<Vec<Value>>> {
         (async move {
             let mut connection = self.connection.lock().unwrap();
-            connection.received = cmd.cmd_iter().cloned().collect();
-            Ok(connection.to_send.clone())
+            connection.round_trips += 1;
+            connection.received.extend(cmd.cmd_iter().cloned());
+            match connection.pipelined.pop() {
+                Some(values) => Ok(values),
+                None => Ok(connection.to_send.clone()),
+            }
         })
         .boxed()
     }
 
+    // Yields before answering so concurrent callers sharing a store interleave their
+    // commands instead of running to completion one after the other.
     fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> BoxFuture<'a, RedisResult<Value>> {
         (async move {
+            let () = actix_rt::task::yield_now().await;
             let mut connection = self.connection.lock().unwrap();
+            connection.round_trips += 1;
             connection.received.push(cmd.clone());
             Ok(connection.to_send.pop().unwrap())
         })
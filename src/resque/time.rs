@@ -0,0 +1,61 @@
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+
+// Formats with an explicit offset, as written by Ruby's Time#to_s and Time#iso8601.
+const OFFSET_FORMATS: [&str; 3] = [
+    "%Y-%m-%d %H:%M:%S %z",
+    "%Y/%m/%d %H:%M:%S %z",
+    "%Y-%m-%dT%H:%M:%S%z",
+];
+
+// Formats ending in a zone name. Resque always writes these in UTC.
+const UTC_FORMATS: [&str; 2] = ["%Y/%m/%d %H:%M:%S", "%Y-%m-%d %H:%M:%S"];
+
+/// Parses the timestamps Resque and its plugins store in Redis. Depending on the version
+/// these are ISO 8601, `Time#to_s` output, Resque's own `%Y/%m/%d %H:%M:%S %Z` format or
+/// a unix timestamp.
+pub fn parse_time(val: &str) -> Option<DateTime<Utc>> {
+    let val = val.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(val) {
+        return Some(time.with_timezone(&Utc));
+    }
+    for format in OFFSET_FORMATS.iter() {
+        if let Ok(time) = DateTime::parse_from_str(val, format) {
+            return Some(time.with_timezone(&Utc));
+        }
+    }
+    let naive = val
+        .strip_suffix(" UTC")
+        .or_else(|| val.strip_suffix(" GMT"))
+        .unwrap_or(val);
+    for format in UTC_FORMATS.iter() {
+        if let Ok(time) = NaiveDateTime::parse_from_str(naive, format) {
+            return Some(Utc.from_utc_datetime(&time));
+        }
+    }
+    if let Ok(seconds) = val.parse::<f64>() {
+        return Utc.timestamp_opt(seconds.trunc() as i64, 0).single();
+    }
+    None
+}
+
+/// Formats a time the way Resque writes `failed_at` and `retried_at`.
+pub fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y/%m/%d %H:%M:%S UTC").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_resque_formats() {
+        let expected = Utc.with_ymd_and_hms(2021, 3, 1, 12, 0, 0).unwrap();
+        assert_eq!(parse_time("2021/03/01 12:00:00 UTC"), Some(expected));
+        assert_eq!(parse_time("2021-03-01 07:00:00 -0500"), Some(expected));
+        assert_eq!(parse_time("2021-03-01T12:00:00+00:00"), Some(expected));
+        assert_eq!(parse_time("2021-03-01T12:00:00Z"), Some(expected));
+        assert_eq!(parse_time("1614600000"), Some(expected));
+        assert_eq!(parse_time("yesterday"), None);
+        assert_eq!(parse_time(&format_time(expected)), Some(expected));
+    }
+}
@@ -0,0 +1,450 @@
+use super::failure::{Failure, JobPayload};
+use super::time::{format_time, parse_time};
+use super::Namespace;
+use chrono::{DateTime, Duration, Utc};
+use redis::{AsyncCommands, ErrorKind, Value};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Seconds a worker can go without a heartbeat before it's treated as dead. Resque
+/// prunes after five missed heartbeats of a minute each.
+pub const DEFAULT_STALE_AFTER: i64 = 300;
+
+#[derive(Serialize)]
+pub struct Worker {
+    id: String,
+    host: Option<String>,
+    pid: Option<u32>,
+    queues: Vec<String>,
+    job: Option<CurrentJob>,
+    errors: Vec<String>,
+    runtime: Option<i64>,
+    started_at: Option<DateTime<Utc>>,
+    processed: u64,
+    failed: u64,
+    heartbeat: Option<String>,
+    heartbeat_at: Option<DateTime<Utc>>,
+    stale: bool,
+}
+
+/// What `worker:<id>` holds while the worker is performing a job.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CurrentJob {
+    queue: Option<String>,
+    run_at: Option<String>,
+    payload: JobPayload,
+}
+
+// Resque builds worker ids from `hostname:pid:queue,queue`.
+pub(super) fn split_id(id: &str) -> (Option<String>, Option<u32>, Vec<String>) {
+    let mut parts = id.splitn(3, ':');
+    let host = parts
+        .next()
+        .filter(|host| !host.is_empty())
+        .map(String::from);
+    let pid = parts.next().and_then(|pid| pid.parse().ok());
+    let queues = parts
+        .next()
+        .map(|queues| queues.split(',').map(String::from).collect())
+        .unwrap_or_default();
+    (host, pid, queues)
+}
+
+/// Whether the worker with this id takes jobs from `queue`. Resque allows `*` in the
+/// queue names a worker is started with as a wildcard.
+pub(super) fn listens_on(id: &str, queue: &str) -> bool {
+    split_id(id)
+        .2
+        .iter()
+        .any(|pattern| glob_match(pattern, queue))
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => match name.strip_prefix(prefix) {
+            Some(tail) => tail
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(tail.len()))
+                .any(|i| glob_match(rest, &tail[i..])),
+            None => false,
+        },
+    }
+}
+
+// Workers that never sent a heartbeat predate heartbeats in Resque, like Resque itself
+// they're left alone rather than guessed at.
+fn is_stale(heartbeat: Option<DateTime<Utc>>, now: DateTime<Utc>, stale_after: Duration) -> bool {
+    heartbeat.is_some_and(|at| now - at > stale_after)
+}
+
+// Workers whose keys are fetched with a single MGET, four keys per worker.
+const WORKER_BATCH: usize = 100;
+
+fn worker_keys(ns: &Namespace, worker: &str) -> [String; 4] {
+    [
+        ns.key(&format!("worker:{}", worker)),
+        ns.key(&format!("worker:{}:started", worker)),
+        ns.key(&format!("stat:processed:{}", worker)),
+        ns.key(&format!("stat:failed:{}", worker)),
+    ]
+}
+
+// Decodes one MGET reply, recording why it couldn't be read instead of failing the
+// whole listing over a single bad key.
+fn decode<T: redis::FromRedisValue>(
+    value: &Value,
+    key: &str,
+    errors: &mut Vec<String>,
+) -> Option<T> {
+    match redis::from_redis_value::<Option<T>>(value) {
+        Ok(val) => val,
+        Err(err) => {
+            errors.push(format!("unable to read {}: {}", key, err));
+            None
+        }
+    }
+}
+
+fn worker_details(
+    id: String,
+    values: &[Value],
+    heartbeat: Option<String>,
+    now: DateTime<Utc>,
+    stale_after: Duration,
+) -> Worker {
+    let mut errors = Vec::new();
+    let working_on: Option<String> = decode(&values[0], "worker", &mut errors);
+    let started: Option<String> = decode(&values[1], "started", &mut errors);
+    let processed: Option<u64> = decode(&values[2], "stat:processed", &mut errors);
+    let failed: Option<u64> = decode(&values[3], "stat:failed", &mut errors);
+    let job: Option<CurrentJob> = working_on.and_then(|raw| match serde_json::from_str(&raw) {
+        Ok(job) => Some(job),
+        Err(err) => {
+            errors.push(format!("unable to parse current job: {}", err));
+            None
+        }
+    });
+    let (host, pid, queues) = split_id(&id);
+    let heartbeat_at = heartbeat.as_deref().and_then(parse_time);
+    Worker {
+        runtime: job
+            .as_ref()
+            .and_then(|job| job.run_at.as_deref())
+            .and_then(parse_time)
+            .map(|run_at| (now - run_at).num_seconds()),
+        started_at: started.as_deref().and_then(parse_time),
+        processed: processed.unwrap_or(0),
+        failed: failed.unwrap_or(0),
+        stale: is_stale(heartbeat_at, now, stale_after),
+        host,
+        pid,
+        queues,
+        job,
+        errors,
+        heartbeat,
+        heartbeat_at,
+        id,
+    }
+}
+
+/// Lists registered workers along with what they're working on. Everything past the
+/// worker set is read with batched MGETs in a single round trip, and a worker whose keys
+/// can't be read is still listed with the problem in `errors`.
+pub async fn active_workers(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    stale_after: Duration,
+) -> redis::RedisResult<Vec<Worker>> {
+    let (workers, mut heartbeats): (Vec<String>, HashMap<String, String>) = redis::pipe()
+        .smembers(ns.key("workers"))
+        .hgetall(ns.key("workers:heartbeat"))
+        .query_async(&mut con)
+        .await?;
+    if workers.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut pipe = redis::pipe();
+    for batch in workers.chunks(WORKER_BATCH) {
+        let keys: Vec<String> = batch
+            .iter()
+            .flat_map(|worker| worker_keys(ns, worker))
+            .collect();
+        pipe.cmd("MGET").arg(keys);
+    }
+    let batches: Vec<Vec<Value>> = pipe.query_async(&mut con).await?;
+    let values: Vec<Value> = batches.into_iter().flatten().collect();
+    if values.len() != workers.len() * 4 {
+        return Err(From::from((
+            ErrorKind::ResponseError,
+            "unexpected number of worker values",
+        )));
+    }
+    let now = Utc::now();
+    Ok(workers
+        .into_iter()
+        .zip(values.chunks(4))
+        .map(|(id, values)| {
+            let heartbeat = heartbeats.remove(&id);
+            worker_details(id, values, heartbeat, now, stale_after)
+        })
+        .collect())
+}
+
+// Queues the same cleanup Resque's `unregister_worker` does.
+fn unregister(pipe: &mut redis::Pipeline, ns: &Namespace, id: &str) {
+    pipe.del(ns.key(&format!("stat:processed:{}", id)))
+        .ignore()
+        .del(ns.key(&format!("stat:failed:{}", id)))
+        .ignore()
+        .srem(ns.key("workers"), id)
+        .ignore()
+        .hdel(ns.key("workers:heartbeat"), id)
+        .ignore()
+        .del(ns.key(&format!("worker:{}", id)))
+        .ignore()
+        .del(ns.key(&format!("worker:{}:started", id)))
+        .ignore();
+}
+
+pub async fn remove_worker(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    id: &str,
+) -> redis::RedisResult<()> {
+    let mut pipe = redis::pipe();
+    unregister(&mut pipe, ns, id);
+    pipe.query_async(&mut con).await
+}
+
+// The failure Resque records for a job whose worker died while performing it.
+fn dirty_exit(raw: &str, id: &str, now: DateTime<Utc>) -> Option<String> {
+    let working_on: CurrentJob = serde_json::from_str(raw).ok()?;
+    let failure = Failure {
+        failed_at: Some(format_time(now)),
+        payload: working_on.payload,
+        exception: Some(String::from("Resque::DirtyExit")),
+        error: Some(format!(
+            "Worker {} was pruned after its heartbeat stopped",
+            id
+        )),
+        backtrace: Vec::new(),
+        worker: Some(id.to_string()),
+        queue: working_on.queue,
+        retried_at: None,
+        retried_payload: None,
+    };
+    serde_json::to_string(&failure).ok()
+}
+
+/// Unregisters every worker whose heartbeat is older than `stale_after`, the way Resque's
+/// `prune_dead_workers` does. A job the worker was performing is recorded as failed so it
+/// isn't silently lost. Returns the ids of the pruned workers.
+pub async fn prune_dead_workers(
+    mut con: impl AsyncCommands,
+    ns: &Namespace,
+    stale_after: Duration,
+) -> redis::RedisResult<Vec<String>> {
+    let heartbeats: HashMap<String, String> = con.hgetall(ns.key("workers:heartbeat")).await?;
+    let now = Utc::now();
+    let mut stale: Vec<String> = heartbeats
+        .into_iter()
+        .filter(|(_, heartbeat)| is_stale(parse_time(heartbeat), now, stale_after))
+        .map(|(id, _)| id)
+        .collect();
+    stale.sort();
+    for id in stale.iter() {
+        let working_on: Option<String> = con.get(ns.key(&format!("worker:{}", id))).await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        if let Some(failure) = working_on.and_then(|raw| dirty_exit(&raw, id, now)) {
+            pipe.rpush(ns.key("failed"), failure)
+                .ignore()
+                .incr(ns.key("stat:failed"), 1)
+                .ignore();
+        }
+        unregister(&mut pipe, ns, id);
+        pipe.query_async::<_, ()>(&mut con).await?;
+    }
+    Ok(stale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::mock_redis::RedisStore;
+    use super::super::tests::{command_args, data_list};
+    use super::*;
+
+    fn ago(seconds: i64) -> String {
+        (Utc::now() - Duration::seconds(seconds)).to_rfc3339()
+    }
+
+    #[actix_rt::test]
+    async fn flags_workers_with_old_heartbeats() {
+        let (alive, dead) = (ago(30), ago(600));
+        let store = RedisStore::new(Vec::new(), Vec::new()).pipelined(vec![
+            vec![Value::Bulk(vec![Value::Nil; 12])],
+            vec![
+                data_list(&["alive:1:*", "dead:2:*", "old:3:*"]),
+                data_list(&["alive:1:*", &alive, "dead:2:*", &dead]),
+            ],
+        ]);
+        let workers = active_workers(store, &Namespace::default(), Duration::seconds(300))
+            .await
+            .unwrap();
+        let stale: Vec<(&str, bool)> = workers.iter().map(|w| (w.id.as_str(), w.stale)).collect();
+        assert_eq!(
+            stale,
+            vec![("alive:1:*", false), ("dead:2:*", true), ("old:3:*", false)]
+        );
+        assert!(workers[1].heartbeat_at.is_some());
+        assert!(workers[2].heartbeat_at.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn worker_details_are_parsed() {
+        let run_at = (Utc::now() - Duration::seconds(90)).format("%Y/%m/%d %H:%M:%S UTC");
+        let working_on = format!(
+            r#"{{"queue":"mail","run_at":"{}","payload":{{"class":"Mailer","args":[1]}}}}"#,
+            run_at
+        );
+        let store = RedisStore::new(Vec::new(), Vec::new()).pipelined(vec![
+            vec![Value::Bulk(vec![
+                Value::Data(Vec::from(working_on)),
+                Value::Data(Vec::from("2021-03-01 12:00:00 +0000")),
+                Value::Data(Vec::from("12")),
+                Value::Data(Vec::from("2")),
+                Value::Data(Vec::from("not json")),
+                Value::Nil,
+                Value::Data(Vec::from("lots")),
+                Value::Nil,
+            ])],
+            vec![
+                data_list(&["web.1:4021:mail,default", "web.2:77:*"]),
+                data_list(&[]),
+            ],
+        ]);
+        let workers = active_workers(
+            store.clone(),
+            &Namespace::new("jobs"),
+            Duration::seconds(300),
+        )
+        .await
+        .unwrap();
+        let busy = &workers[0];
+        assert_eq!(busy.host.as_deref(), Some("web.1"));
+        assert_eq!(busy.pid, Some(4021));
+        assert_eq!(busy.queues, vec!["mail", "default"]);
+        assert_eq!(busy.job.as_ref().unwrap().payload.class, "Mailer");
+        assert_eq!(busy.job.as_ref().unwrap().queue.as_deref(), Some("mail"));
+        assert!((90..100).contains(&busy.runtime.unwrap()));
+        assert!(busy.started_at.is_some());
+        assert_eq!((busy.processed, busy.failed), (12, 2));
+        let broken = &workers[1];
+        assert!(broken.job.is_none());
+        assert_eq!(broken.errors.len(), 2);
+        assert!(broken.errors[0].starts_with("unable to read stat:processed"));
+        assert!(broken.errors[1].starts_with("unable to parse current job"));
+        assert_eq!((broken.processed, broken.failed), (0, 0));
+        assert!(busy.errors.is_empty());
+        let connection = store.connection.lock().unwrap();
+        let mget = command_args(&connection.received[2]);
+        assert_eq!(mget.len(), 9);
+        assert_eq!(mget[0], "MGET");
+        assert_eq!(mget[1], "jobs:worker:web.1:4021:mail,default");
+        assert_eq!(mget[8], "jobs:stat:failed:web.2:77:*");
+    }
+
+    #[actix_rt::test]
+    async fn large_worker_sets_take_two_round_trips() {
+        let ids: Vec<String> = (0..1000).map(|pid| format!("web:{}:*", pid)).collect();
+        let id_refs: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let batches: Vec<Value> = (0..10)
+            .map(|_| {
+                let mut values = Vec::new();
+                for _ in 0..100 {
+                    values.push(Value::Data(Vec::from(
+                        r#"{"queue":"a","payload":{"class":"A"}}"#,
+                    )));
+                    values.push(Value::Nil);
+                    values.push(Value::Data(Vec::from("5")));
+                    values.push(Value::Nil);
+                }
+                Value::Bulk(values)
+            })
+            .collect();
+        let store = RedisStore::new(Vec::new(), Vec::new())
+            .pipelined(vec![batches, vec![data_list(&id_refs), data_list(&[])]]);
+        let workers = active_workers(store.clone(), &Namespace::default(), Duration::seconds(300))
+            .await
+            .unwrap();
+        assert_eq!(workers.len(), 1000);
+        assert!(workers.iter().all(|w| w.job.is_some() && w.processed == 5));
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(connection.round_trips, 2);
+        // Ten MGETs of a hundred workers each after SMEMBERS and HGETALL.
+        assert_eq!(connection.received.len(), 12);
+    }
+
+    #[test]
+    fn matches_queue_wildcards() {
+        assert!(listens_on("host:1:mail,default", "default"));
+        assert!(listens_on("host:1:*", "anything"));
+        assert!(listens_on("host:1:mail_*", "mail_high"));
+        assert!(!listens_on("host:1:mail_*", "default"));
+        assert!(!listens_on("host:1:mail", "mail_high"));
+    }
+
+    #[test]
+    fn splits_worker_ids() {
+        assert_eq!(
+            split_id("host:12:a,b"),
+            (
+                Some(String::from("host")),
+                Some(12),
+                vec![String::from("a"), String::from("b")]
+            )
+        );
+        assert_eq!(
+            split_id("odd"),
+            (Some(String::from("odd")), None, Vec::new())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn prunes_dead_workers_and_fails_their_jobs() {
+        let working_on = r#"{"queue":"mail","run_at":"2021/03/01 12:00:00 UTC","payload":{"class":"Mailer","args":[1]}}"#;
+        let (alive, dead) = (ago(30), ago(600));
+        let store = RedisStore::new(
+            Vec::new(),
+            vec![
+                Value::Data(Vec::from(working_on)),
+                data_list(&["alive:1:*", &alive, "dead:2:*", &dead]),
+            ],
+        )
+        .pipelined(vec![vec![Value::Bulk(Vec::new())]]);
+        let pruned =
+            prune_dead_workers(store.clone(), &Namespace::default(), Duration::seconds(300))
+                .await
+                .unwrap();
+        assert_eq!(pruned, vec!["dead:2:*"]);
+        let connection = store.connection.lock().unwrap();
+        assert_eq!(
+            command_args(&connection.received[1]),
+            vec!["GET", "resque:worker:dead:2:*"]
+        );
+        let rpush = command_args(&connection.received[2]);
+        assert_eq!(rpush[1], "resque:failed");
+        let failure = Failure::parse(&rpush[2]).unwrap();
+        assert_eq!(failure.exception.as_deref(), Some("Resque::DirtyExit"));
+        assert_eq!(failure.queue.as_deref(), Some("mail"));
+        assert_eq!(failure.payload.class, "Mailer");
+        assert_eq!(connection.received.len(), 10);
+    }
+
+    #[test]
+    fn missing_heartbeats_are_never_stale() {
+        assert!(!is_stale(None, Utc::now(), Duration::seconds(1)));
+    }
+}
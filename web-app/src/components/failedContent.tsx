@@ -58,11 +58,10 @@ const FailedContent: React.FC = () => {
     });
   };
 
-  const handleJobDeleted = (id: string) => {
-    const filteredJobs = failedJobs.jobs.filter(
-      job => job.payload.args[0].job_id !== id
-    );
-    setJobs({ total_failed: failedJobs.total_failed - 1, jobs: filteredJobs });
+  // Job ids carry their position in the failed list, so after any change the page is
+  // fetched again rather than patched, otherwise the rows below keep stale ids.
+  const reloadPage = () => {
+    triggerReset(!wasReset);
   };
 
   const handleRetry = () => {
@@ -132,9 +131,10 @@ const FailedContent: React.FC = () => {
             {failedJobs.jobs.map(item => (
               <FailedRow
                 job={item}
-                onJobDeleted={handleJobDeleted}
-                onJobRetried={handleJobDeleted}
-                key={item.payload.args[0].job_id}
+                onJobDeleted={reloadPage}
+                onJobRetried={reloadPage}
+                onJobMoved={reloadPage}
+                key={item.id}
               />
             ))}
           </List>
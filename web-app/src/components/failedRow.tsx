@@ -14,18 +14,22 @@ import { QueueJob, renderArguments } from "../utils/jobArgs";
 import BASE_PATH from "../utils/basePath";
 
 export interface FailedJob {
+  id: string;
   backtrace: Array<string>;
   error: string;
   exception: string;
   failed_at: string;
   payload: QueueJob;
   queue: string;
+  retried_at?: string;
+  retried_payload?: QueueJob;
 }
 
 interface FailedProps {
   job: FailedJob;
   onJobDeleted(id: string): void;
   onJobRetried(id: string): void;
+  onJobMoved(id: string): void;
 }
 
 const useStyles = makeStyles((theme: Theme) =>
@@ -74,18 +78,21 @@ function request(jobID: string, method: string): RequestBody {
 const FailedRow: React.FC<FailedProps> = ({
   job,
   onJobDeleted,
-  onJobRetried
+  onJobRetried,
+  onJobMoved
 }) => {
   const classes = useStyles();
   const [deleting, setDeleting] = React.useState(false);
   const [retrying, setRetrying] = React.useState(false);
   const handleDeleteJob = () => {
-    const jobID = job.payload.args[0].job_id;
+    const jobID = job.id;
     setDeleting(true);
     fetch(`${BASE_PATH}/api/failed_job`, request(jobID, "DELETE")).then(
       response => {
         if (response.ok) {
           onJobDeleted(jobID);
+        } else if (response.status === 409) {
+          onJobMoved(jobID);
         } else {
           console.error(`unable to delete job ${jobID}`);
           setDeleting(false);
@@ -94,12 +101,14 @@ const FailedRow: React.FC<FailedProps> = ({
     );
   };
   const handleRetryJob = () => {
-    const jobID = job.payload.args[0].job_id;
+    const jobID = job.id;
     setRetrying(true);
     fetch(`${BASE_PATH}/api/retry_job`, request(jobID, "POST")).then(
       response => {
         if (response.ok) {
           onJobRetried(jobID);
+        } else if (response.status === 409) {
+          onJobMoved(jobID);
         } else {
           console.error(`unable to retry job ${jobID}`);
           setRetrying(false);
@@ -125,6 +134,11 @@ const FailedRow: React.FC<FailedProps> = ({
                 {trimmedError(job.error)}
               </Typography>
               <Typography variant="body2">{job.failed_at}</Typography>
+              {job.retried_at && (
+                <Typography variant="body2" color="textSecondary">
+                  Retried {job.retried_at}
+                </Typography>
+              )}
             </Grid>
           </Grid>
         </ExpansionPanelSummary>
@@ -16,7 +16,7 @@ import BASE_PATH from "../utils/basePath";
 
 interface Worker {
   id: string;
-  payload?: string;
+  job?: Payload;
   heartbeat?: string;
 }
 
@@ -57,10 +57,10 @@ function payloadString(job: Payload): string {
 }
 
 function workerText(item: Worker) {
-  if (!item.payload) {
+  if (!item.job) {
     return <ListItemText primary={item.id} secondary="waiting..." />;
   }
-  const argList: Payload = JSON.parse(item.payload);
+  const argList: Payload = item.job;
   return (
     <ListItemText
       primary={`${item.id} - ${argList.payload.args[0].job_class}`}
@@ -110,7 +110,7 @@ const Workers = () => {
       </React.Fragment>
     );
   };
-  const working = workers.filter(worker => !!worker.payload);
+  const working = workers.filter(worker => !!worker.job);
   return (
     <div className={classes.mainContent}>
       <Typography variant="h6">